@@ -0,0 +1,445 @@
+use crate::cpu::Flags;
+use crate::instruction::Operation;
+
+// Mask covering every bit of an operand of the given width
+pub fn width_mask(wide: bool) -> u16 {
+    if wide { 0xffff } else { 0xff }
+}
+
+// Mask selecting the sign (most significant) bit of an operand of the given width
+pub fn sign_bit(wide: bool) -> u16 {
+    if wide { 0x8000 } else { 0x80 }
+}
+
+// ZF, SF and PF depend only on the result. PF looks at the low byte regardless of width.
+pub fn set_result_flags(flags: &mut Flags, result: u16, wide: bool) {
+    let result = result & width_mask(wide);
+    flags.zf = result == 0;
+    flags.sf = (result & sign_bit(wide)) != 0;
+    flags.pf = (result as u8).count_ones().is_multiple_of(2);
+}
+
+// dest + src + carry_in, setting CF, PF, AF, ZF, SF and OF
+pub fn add_with_carry(flags: &mut Flags, dest: u16, src: u16, carry_in: bool, wide: bool) -> u16 {
+    let mask = width_mask(wide);
+    let (dest, src) = (dest & mask, src & mask);
+    let sum = (dest as u32) + (src as u32) + (carry_in as u32);
+    let result = (sum as u16) & mask;
+
+    flags.cf = sum > (mask as u32);
+    flags.af = ((dest ^ src ^ result) & 0x10) != 0;
+    // Signed overflow: both operands share a sign that differs from the result's
+    flags.of = ((dest ^ result) & (src ^ result) & sign_bit(wide)) != 0;
+    set_result_flags(flags, result, wide);
+    result
+}
+
+// dest - src - borrow_in, setting CF, PF, AF, ZF, SF and OF
+pub fn sub_with_borrow(flags: &mut Flags, dest: u16, src: u16, borrow_in: bool, wide: bool) -> u16 {
+    let mask = width_mask(wide);
+    let (dest, src) = (dest & mask, src & mask);
+    let subtrahend = (src as u32) + (borrow_in as u32);
+    let result = (dest as u32).wrapping_sub(subtrahend) as u16 & mask;
+
+    flags.cf = (dest as u32) < subtrahend;
+    flags.af = ((dest ^ src ^ result) & 0x10) != 0;
+    // Signed overflow: operands have different signs and the result's sign differs from dest
+    flags.of = ((dest ^ src) & (dest ^ result) & sign_bit(wide)) != 0;
+    set_result_flags(flags, result, wide);
+    result
+}
+
+pub fn add(flags: &mut Flags, dest: u16, src: u16, wide: bool) -> u16 {
+    add_with_carry(flags, dest, src, false, wide)
+}
+
+pub fn sub(flags: &mut Flags, dest: u16, src: u16, wide: bool) -> u16 {
+    sub_with_borrow(flags, dest, src, false, wide)
+}
+
+// AND, OR, XOR and TEST clear CF and OF and set ZF, SF and PF from the result
+fn logic_flags(flags: &mut Flags, result: u16, wide: bool) -> u16 {
+    let result = result & width_mask(wide);
+    flags.cf = false;
+    flags.of = false;
+    flags.af = false; // Undefined on the 8086, cleared like most implementations do
+    set_result_flags(flags, result, wide);
+    result
+}
+
+pub fn and(flags: &mut Flags, dest: u16, src: u16, wide: bool) -> u16 {
+    logic_flags(flags, dest & src, wide)
+}
+
+pub fn or(flags: &mut Flags, dest: u16, src: u16, wide: bool) -> u16 {
+    logic_flags(flags, dest | src, wide)
+}
+
+pub fn xor(flags: &mut Flags, dest: u16, src: u16, wide: bool) -> u16 {
+    logic_flags(flags, dest ^ src, wide)
+}
+
+// INC and DEC set flags like ADD and SUB by one, except that CF is preserved
+pub fn inc(flags: &mut Flags, dest: u16, wide: bool) -> u16 {
+    let carry = flags.cf;
+    let result = add(flags, dest, 1, wide);
+    flags.cf = carry;
+    result
+}
+
+pub fn dec(flags: &mut Flags, dest: u16, wide: bool) -> u16 {
+    let carry = flags.cf;
+    let result = sub(flags, dest, 1, wide);
+    flags.cf = carry;
+    result
+}
+
+// NEG is 0 - dest, so CF is set for every operand except zero
+pub fn neg(flags: &mut Flags, dest: u16, wide: bool) -> u16 {
+    sub(flags, 0, dest, wide)
+}
+
+// Sign extends an operand of the given width to 32 bits
+fn sign_extend(val: u16, wide: bool) -> i32 {
+    if wide { val as i16 as i32 } else { val as u8 as i8 as i32 }
+}
+
+// Unsigned multiply. CF and OF are set when the upper half of the product is non-zero.
+pub fn mul(flags: &mut Flags, acc: u16, src: u16, wide: bool) -> u32 {
+    let mask = width_mask(wide) as u32;
+    let product = ((acc as u32) & mask) * ((src as u32) & mask);
+    let bits = if wide { 16 } else { 8 };
+    flags.cf = (product >> bits) != 0;
+    flags.of = flags.cf;
+    product
+}
+
+// Signed multiply. CF and OF are set when the product doesn't fit in the lower half.
+pub fn imul(flags: &mut Flags, acc: u16, src: u16, wide: bool) -> u32 {
+    let product = sign_extend(acc, wide) * sign_extend(src, wide);
+    let fits = if wide {
+        product == (product as i16 as i32)
+    } else {
+        product == (product as i8 as i32)
+    };
+    flags.cf = !fits;
+    flags.of = !fits;
+    let mask: u32 = if wide { 0xffff_ffff } else { 0xffff };
+    (product as u32) & mask
+}
+
+// Unsigned divide of a double-width dividend, returning (quotient, remainder).
+// None means a divide error: division by zero or a quotient too large for the destination.
+pub fn div(dividend: u32, divisor: u16, wide: bool) -> Option<(u16, u16)> {
+    let divisor = (divisor & width_mask(wide)) as u32;
+    if divisor == 0 {
+        return None;
+    }
+    let quotient = dividend / divisor;
+    if quotient > (width_mask(wide) as u32) {
+        return None;
+    }
+    Some((quotient as u16, (dividend % divisor) as u16))
+}
+
+// Signed divide, truncating towards zero. The remainder takes the sign of the dividend.
+// The 8086 raises a divide error for the most negative quotient (-128 or -32768) too.
+pub fn idiv(dividend: u32, divisor: u16, wide: bool) -> Option<(u16, u16)> {
+    let dividend = if wide { dividend as i32 as i64 } else { dividend as u16 as i16 as i64 };
+    let divisor = sign_extend(divisor, wide) as i64;
+    if divisor == 0 {
+        return None;
+    }
+    let quotient = dividend / divisor;
+    let limit: i64 = if wide { 0x7fff } else { 0x7f };
+    if quotient > limit || quotient < -limit {
+        return None;
+    }
+    let mask = width_mask(wide);
+    Some(((quotient as u16) & mask, ((dividend % divisor) as u16) & mask))
+}
+
+// Decimal adjust after packed BCD addition. OF is undefined and left alone.
+pub fn daa(flags: &mut Flags, al: u8) -> u8 {
+    let (old_al, old_cf) = (al, flags.cf);
+    let mut al = al;
+    if (al & 0x0f) > 9 || flags.af {
+        let (sum, carry) = al.overflowing_add(6);
+        al = sum;
+        flags.cf = old_cf || carry;
+        flags.af = true;
+    } else {
+        flags.af = false;
+    }
+    if old_al > 0x99 || old_cf {
+        al = al.wrapping_add(0x60);
+        flags.cf = true;
+    } else {
+        flags.cf = false;
+    }
+    set_result_flags(flags, al as u16, false);
+    al
+}
+
+// Decimal adjust after packed BCD subtraction. OF is undefined and left alone.
+pub fn das(flags: &mut Flags, al: u8) -> u8 {
+    let (old_al, old_cf) = (al, flags.cf);
+    let mut al = al;
+    if (al & 0x0f) > 9 || flags.af {
+        let (difference, borrow) = al.overflowing_sub(6);
+        al = difference;
+        flags.cf = old_cf || borrow;
+        flags.af = true;
+    } else {
+        flags.af = false;
+    }
+    if old_al > 0x99 || old_cf {
+        al = al.wrapping_sub(0x60);
+        flags.cf = true;
+    }
+    set_result_flags(flags, al as u16, false);
+    al
+}
+
+// ASCII adjust after addition (AAA) or subtraction (AAS) on AX. The 8086 adjusts AL alone and
+// carries into AH separately. Only AF and CF are defined, the other flags are left alone.
+pub fn ascii_adjust(flags: &mut Flags, ax: u16, subtract: bool) -> u16 {
+    let (mut ah, mut al) = ((ax >> 8) as u8, ax as u8);
+    let adjust = (al & 0x0f) > 9 || flags.af;
+    if adjust {
+        if subtract {
+            al = al.wrapping_sub(6);
+            ah = ah.wrapping_sub(1);
+        } else {
+            al = al.wrapping_add(6);
+            ah = ah.wrapping_add(1);
+        }
+    }
+    flags.af = adjust;
+    flags.cf = adjust;
+    ((ah as u16) << 8) | ((al & 0x0f) as u16)
+}
+
+// ASCII adjust after multiply: AH = AL / base, AL = AL % base. `base` is 10 unless the
+// (undocumented) immediate is changed. None is a divide error, for a base of 0.
+pub fn aam(flags: &mut Flags, al: u8, base: u8) -> Option<u16> {
+    if base == 0 {
+        return None;
+    }
+    let (ah, al) = (al / base, al % base);
+    set_result_flags(flags, al as u16, false);
+    Some(((ah as u16) << 8) | (al as u16))
+}
+
+// ASCII adjust before division: AL = AL + AH * base, AH = 0. The silicon does this with an
+// ordinary byte add, which is where CF, AF and OF come from.
+pub fn aad(flags: &mut Flags, ax: u16, base: u8) -> u16 {
+    let (ah, al) = ((ax >> 8) as u8, ax as u8);
+    let product = ah.wrapping_mul(base);
+    add(flags, al as u16, product as u16, false)
+}
+
+// Shifts and rotates one bit at a time, `count` times. The count is not masked, as on the 8086.
+// OF is only defined for a count of 1; for larger counts it reflects the last single-bit step.
+pub fn shift(flags: &mut Flags, operation: &Operation, val: u16, count: u8, wide: bool) -> u16 {
+    if count == 0 {
+        return val;
+    }
+
+    let mask = width_mask(wide);
+    let sign = sign_bit(wide);
+    let mut result = val & mask;
+    for _ in 0..count {
+        let msb = (result & sign) != 0;
+        let lsb = (result & 1) != 0;
+        result = match operation {
+            Operation::Rol => ((result << 1) | (msb as u16)) & mask,
+            Operation::Ror => (result >> 1) | (if lsb { sign } else { 0 }),
+            Operation::Rcl => ((result << 1) | (flags.cf as u16)) & mask,
+            Operation::Rcr => (result >> 1) | (if flags.cf { sign } else { 0 }),
+            Operation::Shl => (result << 1) & mask,
+            Operation::Shr => result >> 1,
+            _ => (result >> 1) | (result & sign), // SAR keeps the sign bit
+        };
+        flags.cf = match operation {
+            Operation::Rol | Operation::Rcl | Operation::Shl => msb,
+            _ => lsb,
+        };
+        flags.of = match operation {
+            Operation::Rol | Operation::Rcl | Operation::Shl => ((result & sign) != 0) != flags.cf,
+            Operation::Ror | Operation::Rcr => ((result ^ (result << 1)) & sign) != 0,
+            Operation::Shr => msb,
+            _ => false,
+        };
+    }
+
+    // Rotates only touch CF and OF
+    if matches!(operation, Operation::Shl | Operation::Shr | Operation::Sar) {
+        set_result_flags(flags, result, wide);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Result and the flags it left, as the trace prints them, e.g. "CPAZ"
+    fn flags_after(run: impl FnOnce(&mut Flags) -> u16) -> (u16, String) {
+        let mut flags = Flags::new();
+        let result = run(&mut flags);
+        (result, flags.to_string())
+    }
+
+    #[test]
+    fn add_sets_carry_overflow_and_auxiliary_carry() {
+        assert_eq!(flags_after(|f| add(f, 0x7f, 0x01, false)), (0x80, "ASO".to_string()));
+        assert_eq!(flags_after(|f| add(f, 0xff, 0x01, false)), (0x00, "CPAZ".to_string()));
+        assert_eq!(flags_after(|f| add(f, 0x8000, 0x8000, true)), (0x0000, "CPZO".to_string()));
+        // PF only looks at the low byte
+        assert_eq!(flags_after(|f| add(f, 0x0100, 0x0003, true)), (0x0103, "P".to_string()));
+    }
+
+    #[test]
+    fn sub_borrows_into_carry() {
+        assert_eq!(flags_after(|f| sub(f, 0x00, 0x01, false)), (0xff, "CPAS".to_string()));
+        assert_eq!(flags_after(|f| sub(f, 0x80, 0x01, false)), (0x7f, "AO".to_string()));
+        assert_eq!(flags_after(|f| sub(f, 0x1234, 0x1234, true)), (0x0000, "PZ".to_string()));
+    }
+
+    #[test]
+    fn logic_clears_carry_overflow_and_auxiliary_carry() {
+        let mut flags = Flags::new();
+        flags.cf = true;
+        flags.of = true;
+        flags.af = true;
+        assert_eq!(and(&mut flags, 0xf0f0, 0x8f00, true), 0x8000);
+        assert_eq!(flags.to_string(), "PS");
+        assert_eq!(flags_after(|f| or(f, 0x01, 0x02, false)), (0x03, "P".to_string()));
+        assert_eq!(flags_after(|f| xor(f, 0x5a, 0x5a, false)), (0x00, "PZ".to_string()));
+    }
+
+    #[test]
+    fn inc_and_dec_preserve_carry() {
+        let mut flags = Flags::new();
+        flags.cf = true;
+        assert_eq!(inc(&mut flags, 0x7fff, true), 0x8000);
+        assert_eq!(flags.to_string(), "CPASO");
+        assert_eq!(dec(&mut flags, 0x0001, false), 0x00);
+        assert_eq!(flags.to_string(), "CPZ");
+    }
+
+    #[test]
+    fn neg_sets_carry_unless_the_operand_is_zero() {
+        assert_eq!(flags_after(|f| neg(f, 0x01, false)), (0xff, "CPAS".to_string()));
+        assert_eq!(flags_after(|f| neg(f, 0x00, false)), (0x00, "PZ".to_string()));
+        // -(-128) doesn't fit in a signed byte
+        assert_eq!(flags_after(|f| neg(f, 0x80, false)), (0x80, "CSO".to_string()));
+    }
+
+    #[test]
+    fn mul_and_imul_flag_products_that_overflow_the_low_half() {
+        assert_eq!(flags_after(|f| mul(f, 0x10, 0x10, false) as u16), (0x0100, "CO".to_string()));
+        assert_eq!(flags_after(|f| mul(f, 0x0f, 0x10, false) as u16), (0x00f0, "".to_string()));
+        // -128 * -1 = 128 doesn't fit in a signed byte
+        assert_eq!(flags_after(|f| imul(f, 0x80, 0xff, false) as u16), (0x0080, "CO".to_string()));
+        assert_eq!(flags_after(|f| imul(f, 0xff, 0xff, false) as u16), (0x0001, "".to_string()));
+        let mut flags = Flags::new();
+        assert_eq!(imul(&mut flags, 0xffff, 0x0002, true), 0xffff_fffe);
+        assert_eq!(flags.to_string(), "");
+    }
+
+    #[test]
+    fn div_rejects_zero_and_quotients_too_large() {
+        assert_eq!(div(0x0100, 0x00, false), None);
+        assert_eq!(div(0x0100, 0x01, false), None);
+        assert_eq!(div(0x00ff, 0x10, false), Some((0x0f, 0x0f)));
+        assert_eq!(div(0x0001_0000, 0x0002, true), Some((0x8000, 0x0000)));
+    }
+
+    #[test]
+    fn idiv_truncates_towards_zero_and_rejects_the_most_negative_quotient() {
+        // -7 / 2 = -3 remainder -1
+        assert_eq!(idiv(0xfff9, 0x02, false), Some((0xfd, 0xff)));
+        assert_eq!(idiv(0x00fe, 0x02, false), Some((0x7f, 0x00)));
+        // -256 / 2 = -128 and -65536 / 2 = -32768 are divide errors on the 8086
+        assert_eq!(idiv(0xff00, 0x02, false), None);
+        assert_eq!(idiv(0xffff_0000, 0x0002, true), None);
+        assert_eq!(idiv(0x0000_0001, 0x0000, true), None);
+    }
+
+    #[test]
+    fn shifts_set_overflow_from_the_last_step() {
+        let shl = |val, wide| flags_after(|f| shift(f, &Operation::Shl, val, 1, wide));
+        assert_eq!(shl(0x80, false), (0x00, "CPZO".to_string()));
+        assert_eq!(shl(0x40, false), (0x80, "SO".to_string()));
+        // SHR sets OF to the original sign bit, SAR always clears it
+        assert_eq!(flags_after(|f| shift(f, &Operation::Shr, 0x80, 1, false)), (0x40, "O".to_string()));
+        assert_eq!(flags_after(|f| shift(f, &Operation::Sar, 0x81, 1, false)), (0xc0, "CPS".to_string()));
+        // Rotates leave ZF, SF and PF alone
+        assert_eq!(flags_after(|f| shift(f, &Operation::Rol, 0x80, 1, false)), (0x01, "CO".to_string()));
+        assert_eq!(flags_after(|f| shift(f, &Operation::Ror, 0x8000, 1, true)), (0x4000, "O".to_string()));
+        let mut flags = Flags::new();
+        flags.cf = true;
+        assert_eq!(shift(&mut flags, &Operation::Rcr, 0x01, 1, false), 0x80);
+        assert_eq!(flags.to_string(), "CO");
+        // A count of 0 changes nothing
+        assert_eq!(shift(&mut flags, &Operation::Rcl, 0x00, 0, false), 0x00);
+        assert_eq!(flags.to_string(), "CO");
+    }
+
+    #[test]
+    fn shift_counts_are_not_masked() {
+        assert_eq!(flags_after(|f| shift(f, &Operation::Shl, 0x0001, 17, true)).0, 0x0000);
+        assert_eq!(flags_after(|f| shift(f, &Operation::Rol, 0x0001, 17, true)).0, 0x0002);
+    }
+
+    #[test]
+    fn adc_and_sbb_include_the_carry_in() {
+        assert_eq!(flags_after(|f| add_with_carry(f, 0xffff, 0x0000, true, true)), (0x0000, "CPAZ".to_string()));
+        // The borrow-in alone takes the low nibble below zero
+        assert_eq!(flags_after(|f| sub_with_borrow(f, 0x10, 0x0f, true, false)), (0x00, "PAZ".to_string()));
+        assert_eq!(flags_after(|f| sub_with_borrow(f, 0x8000, 0x0000, true, true)), (0x7fff, "PAO".to_string()));
+        // 0 - 0x7f - 1 is -128, which still fits
+        assert_eq!(flags_after(|f| sub_with_borrow(f, 0x00, 0x7f, true, false)), (0x80, "CAS".to_string()));
+        assert_eq!(flags_after(|f| sub_with_borrow(f, 0x00, 0xffff, true, true)), (0x0000, "CPAZ".to_string()));
+    }
+
+    #[test]
+    fn daa_and_das_adjust_packed_bcd() {
+        // 0x79 + 0x35 = 0xae, adjusted to 0x14 carry 1
+        assert_eq!(flags_after(|f| daa(f, 0xae) as u16), (0x14, "CPA".to_string()));
+        let mut flags = Flags::new();
+        flags.af = true;
+        // 0x38 + 0x29 = 0x61 with AF, adjusted to 0x67
+        assert_eq!(daa(&mut flags, 0x61), 0x67);
+        assert_eq!(flags.to_string(), "A");
+        // 0x35 - 0x47 = 0xee with CF and AF, adjusted to 0x88 borrow 1
+        let mut flags = Flags::new();
+        flags.cf = true;
+        flags.af = true;
+        assert_eq!(das(&mut flags, 0xee), 0x88);
+        assert_eq!(flags.to_string(), "CPAS");
+    }
+
+    #[test]
+    fn aaa_and_aas_adjust_al_and_carry_into_ah() {
+        // 5 + 6 = 0x0b
+        assert_eq!(flags_after(|f| ascii_adjust(f, 0x000b, false)), (0x0101, "CA".to_string()));
+        assert_eq!(flags_after(|f| ascii_adjust(f, 0x0005, false)), (0x0005, "".to_string()));
+        // 8 - 9 = 0xff with AF
+        let mut flags = Flags::new();
+        flags.af = true;
+        assert_eq!(ascii_adjust(&mut flags, 0x02ff, true), 0x0109);
+        assert_eq!(flags.to_string(), "CA");
+    }
+
+    #[test]
+    fn aam_and_aad_use_the_immediate_base() {
+        assert_eq!(flags_after(|f| aam(f, 63, 10).unwrap()), (0x0603, "P".to_string()));
+        assert_eq!(flags_after(|f| aam(f, 0x3f, 16).unwrap()), (0x030f, "P".to_string()));
+        assert_eq!(aam(&mut Flags::new(), 63, 0), None);
+        assert_eq!(flags_after(|f| aad(f, 0x0603, 10)), (0x003f, "P".to_string()));
+        assert_eq!(flags_after(|f| aad(f, 0x0000, 10)), (0x0000, "PZ".to_string()));
+    }
+}
@@ -0,0 +1,428 @@
+use crate::cpu::{ branch_taken, effective_address, CpuModel };
+use crate::instruction::{ AddressingMode, Instruction, Operand, Operation, Register };
+use crate::trace::Snapshot;
+
+// Estimated 8086/8088 clocks for one instruction, split up the way the timing tables in the 8086 manual are
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Clocks {
+    pub base: u32,
+    pub ea: u32, // Effective address calculation, including a segment override
+    pub penalty: u32, // 4 per word transfer at an odd address (8086) or per word transfer (8088)
+}
+
+impl Clocks {
+    pub fn total(&self) -> u32 {
+        self.base + self.ea + self.penalty
+    }
+}
+
+// Rough shape of an operand, which is what the timing tables are keyed on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    None,
+    Register,
+    Segment,
+    Memory,
+    Immediate,
+    Far,
+}
+
+fn kind(operand: &Option<Operand>) -> Kind {
+    match operand {
+        None => Kind::None,
+        Some(Operand::Register(Register::ES | Register::CS | Register::SS | Register::DS)) => Kind::Segment,
+        Some(Operand::Register(_)) => Kind::Register,
+        Some(Operand::Memory(_)) => Kind::Memory,
+        Some(Operand::Immediate { .. }) => Kind::Immediate,
+        Some(Operand::FarAddress { .. }) => Kind::Far,
+    }
+}
+
+// The opcode byte, after any prefixes. Some encodings are faster than the general form.
+fn opcode(instruction: &Instruction) -> u8 {
+    let is_prefix = |byte: &&u8| matches!(**byte, 0x26 | 0x2e | 0x36 | 0x3e | 0xf0 | 0xf2 | 0xf3);
+    instruction.bytes.iter().find(|byte| !is_prefix(byte)).copied().unwrap_or(0)
+}
+
+// Effective address calculation time for each addressing form
+fn ea_clocks(address: &AddressingMode) -> u32 {
+    match address {
+        AddressingMode::Direct(_) => 6,
+        AddressingMode::Register(_) => 0,
+        AddressingMode::Memory { base, index, displacement } => {
+            let registers = match (base, index) {
+                (Some(Register::BP), Some(Register::DI)) | (Some(Register::BX), Some(Register::SI)) => 7,
+                (Some(_), Some(_)) => 8,
+                _ => 5,
+            };
+            registers + if displacement.is_some() { 4 } else { 0 }
+        }
+    }
+}
+
+// Clocks for `instruction` on `model`, given the register state before and after it executed.
+// Jumps, REP counts and shift counts are taken from what actually happened.
+// Only the 8086 and 8088 tables are here, so the 80186 models get None.
+pub fn estimate(
+    instruction: &Instruction,
+    before: &Snapshot,
+    after: &Snapshot,
+    model: CpuModel
+) -> Option<Clocks> {
+    if model.is_80186() {
+        return None;
+    }
+    let registers = &before.registers;
+    let wide = instruction.wide;
+    let (destination, source) = (kind(&instruction.destination), kind(&instruction.source));
+    // From the condition rather than where IP ended up, a taken jump to the next instruction still costs a jump
+    let taken = match instruction.operation {
+        Operation::Into => before.flags.of,
+        ref operation => branch_taken(operation, &before.flags, registers.get(&Register::CX)),
+    };
+    let memory = [&instruction.destination, &instruction.source].into_iter().find_map(|operand| {
+        match operand {
+            Some(Operand::Memory(address)) => Some(address),
+            _ => None,
+        }
+    });
+
+    // Base clocks and the number of transfers to or from the memory operand
+    let (base, transfers) = match instruction.operation {
+        Operation::Mov =>
+            match (destination, source) {
+                _ if matches!(opcode(instruction), 0xa0..=0xa3) => (10, 1),
+                (Kind::Memory, Kind::Immediate) => (10, 1),
+                (Kind::Memory, _) => (9, 1),
+                (_, Kind::Memory) => (8, 1),
+                (_, Kind::Immediate) => (4, 0),
+                _ => (2, 0),
+            }
+        | Operation::Add
+        | Operation::Adc
+        | Operation::Sub
+        | Operation::Sbb
+        | Operation::And
+        | Operation::Or
+        | Operation::Xor =>
+            match (destination, source) {
+                (Kind::Memory, Kind::Immediate) => (17, 2),
+                (Kind::Memory, _) => (16, 2),
+                (_, Kind::Memory) => (9, 1),
+                (_, Kind::Immediate) => (4, 0),
+                _ => (3, 0),
+            }
+        Operation::Cmp =>
+            match (destination, source) {
+                (Kind::Memory, Kind::Immediate) => (10, 1),
+                (Kind::Memory, _) | (_, Kind::Memory) => (9, 1),
+                (_, Kind::Immediate) => (4, 0),
+                _ => (3, 0),
+            }
+        Operation::Test =>
+            match (destination, source) {
+                (Kind::Memory, Kind::Immediate) => (11, 1),
+                (Kind::Memory, _) | (_, Kind::Memory) => (9, 1),
+                _ if matches!(opcode(instruction), 0xa8 | 0xa9) => (4, 0),
+                (_, Kind::Immediate) => (5, 0),
+                _ => (3, 0),
+            }
+        Operation::Inc | Operation::Dec =>
+            match destination {
+                Kind::Memory => (15, 2),
+                _ if matches!(opcode(instruction), 0x40..=0x4f) => (2, 0),
+                _ => (3, 0),
+            }
+        Operation::Not | Operation::Neg => if destination == Kind::Memory { (16, 2) } else { (3, 0) }
+        Operation::Mul | Operation::Imul | Operation::Div | Operation::Idiv => {
+            // The manual gives ranges that depend on the operands, these are the lower bounds
+            let (byte, word) = match instruction.operation {
+                Operation::Mul => (70, 118),
+                Operation::Imul => (80, 128),
+                Operation::Div => (80, 144),
+                _ => (101, 165),
+            };
+            let base = if wide { word } else { byte };
+            if destination == Kind::Memory { (base + 6, 1) } else { (base, 0) }
+        }
+        | Operation::Rol
+        | Operation::Ror
+        | Operation::Rcl
+        | Operation::Rcr
+        | Operation::Shl
+        | Operation::Shr
+        | Operation::Sar => {
+            let by_one = matches!(opcode(instruction), 0xd0 | 0xd1);
+            let count = match instruction.source {
+                Some(Operand::Immediate { value: count, .. }) if !by_one => count as u8,
+                _ => registers.get(&Register::CL) as u8,
+            };
+            let bits = 4 * (count as u32);
+            match (destination, by_one) {
+                (Kind::Memory, false) => (20 + bits, 2),
+                (Kind::Memory, true) => (15, 2),
+                (_, false) => (8 + bits, 0),
+                _ => (2, 0),
+            }
+        }
+        Operation::Push =>
+            match destination {
+                Kind::Memory => (16, 1),
+                Kind::Segment | Kind::Immediate => (10, 0),
+                _ => (11, 0),
+            }
+        Operation::Pop => if destination == Kind::Memory { (17, 1) } else { (8, 0) }
+        Operation::Pushf => (10, 0),
+        Operation::Popf => (8, 0),
+        | Operation::Jnz
+        | Operation::Jz
+        | Operation::Jl
+        | Operation::Jle
+        | Operation::Jb
+        | Operation::Jbe
+        | Operation::Jp
+        | Operation::Jo
+        | Operation::Js
+        | Operation::Jne
+        | Operation::Jnl
+        | Operation::Jg
+        | Operation::Jnb
+        | Operation::Ja
+        | Operation::Jnp
+        | Operation::Jno
+        | Operation::Jns => if taken { (16, 0) } else { (4, 0) }
+        Operation::Loop => if taken { (17, 0) } else { (5, 0) }
+        Operation::Loopz | Operation::Jcxz => if taken { (18, 0) } else { (6, 0) }
+        Operation::Loopnz => if taken { (19, 0) } else { (5, 0) }
+        Operation::Jmp =>
+            match destination {
+                Kind::Memory => (18, 1),
+                Kind::Register => (11, 0),
+                _ => (15, 0),
+            }
+        Operation::JmpFar => if destination == Kind::Memory { (24, 2) } else { (15, 0) }
+        Operation::Call =>
+            match destination {
+                Kind::Memory => (21, 1),
+                Kind::Register => (16, 0),
+                _ => (19, 0),
+            }
+        Operation::CallFar => if destination == Kind::Memory { (37, 2) } else { (28, 0) }
+        Operation::Ret => if destination == Kind::None { (8, 0) } else { (12, 0) }
+        Operation::Retf => if destination == Kind::None { (18, 0) } else { (17, 0) }
+        | Operation::Movs
+        | Operation::Cmps
+        | Operation::Scas
+        | Operation::Lods
+        | Operation::Stos => {
+            // Single execution, REP setup, per repetition, and memory transfers per element
+            let (single, setup, repeated, transfers) = match instruction.operation {
+                Operation::Movs => (18, 9, 17, 2),
+                Operation::Cmps => (22, 9, 22, 2),
+                Operation::Scas => (15, 9, 15, 1),
+                Operation::Lods => (12, 9, 13, 1),
+                _ => (11, 9, 10, 1),
+            };
+            match instruction.prefixes.repeat {
+                Some(_) => {
+                    let cx = registers.get(&Register::CX);
+                    let repetitions = cx.wrapping_sub(after.registers.get(&Register::CX)) as u32;
+                    (setup + repeated * repetitions, transfers * repetitions)
+                }
+                None => (single, transfers),
+            }
+        }
+        Operation::Int => (51, 0),
+        Operation::Int3 => (52, 0),
+        Operation::Into => if taken { (53, 0) } else { (4, 0) }
+        Operation::Iret => (24, 0),
+        Operation::In | Operation::Out => {
+            let immediate_port = destination == Kind::Immediate || source == Kind::Immediate;
+            if immediate_port { (10, 0) } else { (8, 0) }
+        }
+        Operation::Lea => (2, 0),
+        Operation::Lds | Operation::Les => (16, 2),
+        Operation::Xchg =>
+            match (destination, source) {
+                (Kind::Memory, _) | (_, Kind::Memory) => (17, 2),
+                _ if matches!(opcode(instruction), 0x91..=0x97) => (3, 0),
+                _ => (4, 0),
+            }
+        Operation::Xlat => (11, 1),
+        Operation::Cbw => (2, 0),
+        Operation::Cwd => (5, 0),
+        Operation::Nop | Operation::Wait => (3, 0),
+        | Operation::Clc
+        | Operation::Stc
+        | Operation::Cmc
+        | Operation::Cld
+        | Operation::Std
+        | Operation::Cli
+        | Operation::Sti
+        | Operation::Hlt => (2, 0),
+        Operation::Lahf | Operation::Sahf | Operation::Daa | Operation::Das => (4, 0),
+        Operation::Aaa | Operation::Aas => (8, 0),
+        Operation::Aam => (83, 0),
+        Operation::Aad => (60, 0),
+        // 80186 only, and estimate() has given up on those models by now
+        | Operation::Pusha
+        | Operation::Popa
+        | Operation::Enter
+        | Operation::Leave
+        | Operation::Bound
+        | Operation::Ins
+        | Operation::Outs => {
+            return None;
+        }
+    };
+
+    let ea = match memory {
+        Some(address) => {
+            let segment_override = if instruction.prefixes.segment.is_some() { 2 } else { 0 };
+            ea_clocks(address) + segment_override
+        }
+        None => 0,
+    };
+
+    // Word transfers the tables above leave out: the stack, and the vector table for interrupts
+    let (stack_transfers, vector_transfers) = match instruction.operation {
+        Operation::Push | Operation::Pop | Operation::Pushf | Operation::Popf => (1, 0),
+        Operation::Call | Operation::Ret => (1, 0),
+        Operation::CallFar | Operation::Retf => (2, 0),
+        Operation::Iret => (3, 0),
+        Operation::Int | Operation::Int3 => (3, 2),
+        Operation::Into if taken => (3, 2),
+        _ => (0, 0),
+    };
+    let word_transfers = if wide { transfers } else { 0 };
+
+    let penalized_transfers = if model.has_word_bus() {
+        // Word transfers at odd addresses take an extra bus cycle. String instructions transfer at SI and DI.
+        let odd_operand = match instruction.operation {
+            _ if !wide => 0,
+            Operation::Movs | Operation::Cmps => {
+                let odd = (registers.si & 1) + (registers.di & 1);
+                (odd as u32) * transfers / 2
+            }
+            Operation::Lods => ((registers.si & 1) as u32) * transfers,
+            Operation::Scas | Operation::Stos => ((registers.di & 1) as u32) * transfers,
+            _ => {
+                match memory {
+                    Some(address) if effective_address(address, registers) & 1 == 1 => transfers,
+                    _ => 0,
+                }
+            }
+        };
+        let odd_stack = if registers.sp & 1 == 1 { stack_transfers } else { 0 };
+        odd_operand + odd_stack
+    } else {
+        // On an 8-bit bus every word is two transfers
+        word_transfers + stack_transfers + vector_transfers
+    };
+
+    Some(Clocks { base, ea, penalty: 4 * penalized_transfers })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Cpu;
+    use crate::decoder::{ decode, decode_at };
+
+    // Decodes and executes one instruction from CS = 0x100 on a CPU prepared by `setup`, the way main does
+    fn clocks_for(bytes: &[u8], model: CpuModel, setup: impl FnOnce(&mut Cpu)) -> Clocks {
+        let mut cpu = Cpu::new(model);
+        cpu.registers.cs = 0x100;
+        cpu.memory[0x1000..0x1000 + bytes.len()].copy_from_slice(bytes);
+        setup(&mut cpu);
+        let before = Snapshot::of(&cpu);
+        let instruction = decode(&mut cpu).unwrap();
+        cpu.execute(&instruction);
+        estimate(&instruction, &before, &Snapshot::of(&cpu), model).unwrap()
+    }
+
+    fn clocks(base: u32, ea: u32, penalty: u32) -> Clocks {
+        Clocks { base, ea, penalty }
+    }
+
+    #[test]
+    fn effective_address_clocks_follow_the_addressing_form() {
+        let ea = |bytes: &[u8]| clocks_for(bytes, CpuModel::I8086, |_| {}).ea;
+        assert_eq!(ea(&[0x8b, 0x04]), 5); // [si]
+        assert_eq!(ea(&[0x8b, 0x06, 0x04, 0x00]), 6); // [4]
+        assert_eq!(ea(&[0x8b, 0x00]), 7); // [bx + si]
+        assert_eq!(ea(&[0x8b, 0x02]), 8); // [bp + si]
+        assert_eq!(ea(&[0x8b, 0x87, 0xe8, 0x03]), 9); // [bx + 1000]
+        assert_eq!(ea(&[0x8b, 0x43, 0x04]), 11); // [bp + di + 4]
+        assert_eq!(ea(&[0x8b, 0x41, 0x04]), 12); // [bx + di + 4]
+        // A segment override adds 2
+        assert_eq!(ea(&[0x26, 0x8b, 0x00]), 9);
+        assert_eq!(ea(&[0x8b, 0xc3]), 0);
+    }
+
+    #[test]
+    fn word_transfers_at_odd_addresses_cost_4_each() {
+        let odd_bx = |cpu: &mut Cpu| cpu.registers.set(&Register::BX, 1);
+        // mov ax, [bx]
+        assert_eq!(clocks_for(&[0x8b, 0x07], CpuModel::I8086, |_| {}), clocks(8, 5, 0));
+        assert_eq!(clocks_for(&[0x8b, 0x07], CpuModel::I8086, odd_bx), clocks(8, 5, 4));
+        // mov al, [bx] is a byte transfer
+        assert_eq!(clocks_for(&[0x8a, 0x07], CpuModel::I8086, odd_bx), clocks(8, 5, 0));
+        // add [bx], ax reads and writes
+        assert_eq!(clocks_for(&[0x01, 0x07], CpuModel::I8086, odd_bx), clocks(16, 5, 8));
+        // The 8088 pays for every word, even or odd
+        assert_eq!(clocks_for(&[0x8b, 0x07], CpuModel::I8088, |_| {}), clocks(8, 5, 4));
+        // push ax with an odd SP
+        let odd_sp = |cpu: &mut Cpu| cpu.registers.sp = 0x101;
+        assert_eq!(clocks_for(&[0x50], CpuModel::I8086, odd_sp), clocks(11, 0, 4));
+    }
+
+    #[test]
+    fn rep_clocks_scale_with_the_repetitions() {
+        let cx = |count| move |cpu: &mut Cpu| cpu.registers.set(&Register::CX, count);
+        // rep movsb
+        assert_eq!(clocks_for(&[0xf3, 0xa4], CpuModel::I8086, cx(3)), clocks(9 + 17 * 3, 0, 0));
+        assert_eq!(clocks_for(&[0xf3, 0xa4], CpuModel::I8086, cx(0)), clocks(9, 0, 0));
+        // rep movsw from an odd SI, so every read is penalized
+        let odd_si = |cpu: &mut Cpu| {
+            cpu.registers.set(&Register::CX, 3);
+            cpu.registers.si = 1;
+        };
+        assert_eq!(clocks_for(&[0xf3, 0xa5], CpuModel::I8086, odd_si), clocks(9 + 17 * 3, 0, 4 * 3));
+        // repne scasb stopping at the first element
+        assert_eq!(clocks_for(&[0xf2, 0xae], CpuModel::I8086, cx(5)), clocks(9 + 15, 0, 0));
+    }
+
+    #[test]
+    fn shift_clocks_depend_on_the_count() {
+        let cl = |count| move |cpu: &mut Cpu| cpu.registers.set(&Register::CL, count);
+        // shl ax, 1 and shl ax, cl
+        assert_eq!(clocks_for(&[0xd1, 0xe0], CpuModel::I8086, |_| {}), clocks(2, 0, 0));
+        assert_eq!(clocks_for(&[0xd3, 0xe0], CpuModel::I8086, cl(3)), clocks(8 + 4 * 3, 0, 0));
+        // shl word [bx], cl
+        assert_eq!(clocks_for(&[0xd3, 0x27], CpuModel::I8086, cl(2)), clocks(20 + 4 * 2, 5, 0));
+    }
+
+    #[test]
+    fn taken_jumps_are_charged_even_to_the_next_instruction() {
+        let zf = |set| move |cpu: &mut Cpu| cpu.flags.zf = set;
+        // jz $+2
+        assert_eq!(clocks_for(&[0x74, 0x00], CpuModel::I8086, zf(true)).base, 16);
+        assert_eq!(clocks_for(&[0x74, 0x00], CpuModel::I8086, zf(false)).base, 4);
+        // loop $+2
+        let cx = |count| move |cpu: &mut Cpu| cpu.registers.set(&Register::CX, count);
+        assert_eq!(clocks_for(&[0xe2, 0x00], CpuModel::I8086, cx(2)).base, 17);
+        assert_eq!(clocks_for(&[0xe2, 0x00], CpuModel::I8086, cx(1)).base, 5);
+        // jcxz $+2
+        assert_eq!(clocks_for(&[0xe3, 0x00], CpuModel::I8086, cx(0)).base, 18);
+    }
+
+    #[test]
+    fn no_estimate_for_the_80186_models() {
+        for model in [CpuModel::I80186, CpuModel::I80188] {
+            let snapshot = Snapshot::of(&Cpu::new(model));
+            let (instruction, _) = decode_at(&[0x90], 0, model).unwrap();
+            assert_eq!(estimate(&instruction, &snapshot, &snapshot, model), None);
+        }
+    }
+}
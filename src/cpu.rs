@@ -1,9 +1,60 @@
-use crate::instruction::{ AddressingMode, Instruction, Operand, Operation, Register };
-#[derive(Debug, Clone, Copy)]
+use std::fmt;
+use std::ops::RangeInclusive;
+
+use crate::alu;
+use crate::instruction::{ AddressingMode, Instruction, Operand, Operation, Register, Repeat };
+use crate::io::{ IoBus, PortMap };
+// The 8088 is an 8086 with an 8-bit data bus and a shorter prefetch queue.
+// The 80186 and 80188 add a handful of instructions on top of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CpuModel {
+    #[default]
+    I8086,
+    I8088,
+    I80186,
+    I80188,
+}
+
+impl CpuModel {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CpuModel::I8086 => "8086",
+            CpuModel::I8088 => "8088",
+            CpuModel::I80186 => "80186",
+            CpuModel::I80188 => "80188",
+        }
+    }
+
+    // Bytes of instruction prefetch queue
+    pub fn prefetch_queue_size(&self) -> usize {
+        match self {
+            CpuModel::I8086 | CpuModel::I80186 => 6,
+            CpuModel::I8088 | CpuModel::I80188 => 4,
+        }
+    }
+
+    // Whether a word is moved in a single bus cycle, or as two byte transfers costing 4 extra clocks
+    pub fn has_word_bus(&self) -> bool {
+        matches!(self, CpuModel::I8086 | CpuModel::I80186)
+    }
+
+    // PUSHA/POPA, ENTER/LEAVE, BOUND, PUSH imm, IMUL imm, shifts by imm8 and INS/OUTS.
+    // These models also mask shift counts to 5 bits.
+    pub fn is_80186(&self) -> bool {
+        matches!(self, CpuModel::I80186 | CpuModel::I80188)
+    }
+}
+
 pub struct Cpu {
-    pub memory: [u8; 1024 * 1024], // 1MB memory
+    pub model: CpuModel,
+    pub memory: Box<[u8; 1024 * 1024]>, // 1MB memory, on the heap so a Cpu fits on any thread's stack
     pub registers: CPURegisters,
     pub flags: Flags,
+    pub pending_interrupt: Option<u8>, // Vector waiting on the maskable interrupt line
+    pub segment_override: Option<Register>, // Segment prefix of the instruction being executed
+    pub halted: bool, // Set by HLT, cleared when an interrupt is taken
+    pub last_interrupt: Option<u8>, // Vector of the last interrupt taken, callers clear it to watch for the next
+    io: PortMap, // Devices behind IN/OUT, ports none of them claim read 0xff
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -16,6 +67,10 @@ pub struct CPURegisters {
     pub bp: u16,
     pub si: u16,
     pub di: u16,
+    pub es: u16,
+    pub cs: u16,
+    pub ss: u16,
+    pub ds: u16,
     pub ip: u16,
 }
 pub enum Flag {
@@ -24,6 +79,9 @@ pub enum Flag {
     AF,
     ZF,
     SF,
+    TF,
+    IF,
+    DF,
     OF,
 }
 #[derive(Debug, Clone, Copy)]
@@ -33,6 +91,9 @@ pub struct Flags {
     pub af: bool, // auxilary carry
     pub zf: bool, //zero
     pub sf: bool, //sign
+    pub tf: bool, // trap, single-step interrupt after each instruction
+    pub if_: bool, // interrupt enable, masks the interrupt line when clear
+    pub df: bool, // direction, string instructions step SI/DI down when set
     pub of: bool, //overflow
 }
 impl Flags {
@@ -43,6 +104,9 @@ impl Flags {
             af: false,
             zf: false,
             sf: false,
+            tf: false,
+            if_: false,
+            df: false,
             of: false,
         }
     }
@@ -63,6 +127,15 @@ impl Flags {
             Flag::SF => {
                 self.sf = true;
             }
+            Flag::TF => {
+                self.tf = true;
+            }
+            Flag::IF => {
+                self.if_ = true;
+            }
+            Flag::DF => {
+                self.df = true;
+            }
             Flag::OF => {
                 self.of = true;
             }
@@ -85,21 +158,88 @@ impl Flags {
             Flag::SF => {
                 self.sf = false;
             }
+            Flag::TF => {
+                self.tf = false;
+            }
+            Flag::IF => {
+                self.if_ = false;
+            }
+            Flag::DF => {
+                self.df = false;
+            }
             Flag::OF => {
                 self.of = false;
             }
         }
     }
 
+    // Packs the flags into the 16-bit FLAGS register. Bit 1 and bits 12-15 always read as 1 on the 8086.
+    pub fn to_word(&self) -> u16 {
+        0xf002 |
+            (self.cf as u16) |
+            ((self.pf as u16) << 2) |
+            ((self.af as u16) << 4) |
+            ((self.zf as u16) << 6) |
+            ((self.sf as u16) << 7) |
+            ((self.tf as u16) << 8) |
+            ((self.if_ as u16) << 9) |
+            ((self.df as u16) << 10) |
+            ((self.of as u16) << 11)
+    }
+
+    pub fn from_word(word: u16) -> Self {
+        Flags {
+            cf: (word & (1 << 0)) != 0,
+            pf: (word & (1 << 2)) != 0,
+            af: (word & (1 << 4)) != 0,
+            zf: (word & (1 << 6)) != 0,
+            sf: (word & (1 << 7)) != 0,
+            tf: (word & (1 << 8)) != 0,
+            if_: (word & (1 << 9)) != 0,
+            df: (word & (1 << 10)) != 0,
+            of: (word & (1 << 11)) != 0,
+        }
+    }
+
     pub fn reset(&mut self) {
         self.cf = false;
         self.pf = false;
         self.af = false;
         self.zf = false;
         self.sf = false;
+        self.tf = false;
+        self.if_ = false;
+        self.df = false;
         self.of = false;
     }
 }
+// Set flags in FLAGS register bit order, e.g. "CPAS"
+impl fmt::Display for Flags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let flags = [
+            (self.cf, 'C'),
+            (self.pf, 'P'),
+            (self.af, 'A'),
+            (self.zf, 'Z'),
+            (self.sf, 'S'),
+            (self.tf, 'T'),
+            (self.if_, 'I'),
+            (self.df, 'D'),
+            (self.of, 'O'),
+        ];
+        for (set, letter) in flags {
+            if set {
+                write!(f, "{}", letter)?;
+            }
+        }
+        Ok(())
+    }
+}
+impl Default for Flags {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 impl CPURegisters {
     pub fn new() -> Self {
         CPURegisters {
@@ -111,6 +251,10 @@ impl CPURegisters {
             bp: 0,
             si: 0,
             di: 0,
+            es: 0,
+            cs: 0,
+            ss: 0,
+            ds: 0,
             ip: 0,
         }
     }
@@ -125,13 +269,17 @@ impl CPURegisters {
             Register::CH => self.cx[0] as u16,
             Register::CL => self.cx[1] as u16,
             Register::CX => ((self.cx[0] as u16) << 8) + (self.cx[1] as u16),
-            Register::DH => self.cx[0] as u16,
-            Register::DL => self.cx[1] as u16,
+            Register::DH => self.dx[0] as u16,
+            Register::DL => self.dx[1] as u16,
             Register::DX => ((self.dx[0] as u16) << 8) + (self.dx[1] as u16),
             Register::SI => self.si,
             Register::DI => self.di,
             Register::BP => self.bp,
             Register::SP => self.sp,
+            Register::ES => self.es,
+            Register::CS => self.cs,
+            Register::SS => self.ss,
+            Register::DS => self.ds,
         }
     }
     pub fn set(&mut self, reg: &Register, val: u16) {
@@ -188,258 +336,1277 @@ impl CPURegisters {
             Register::SP => {
                 self.sp = val;
             }
+            Register::ES => {
+                self.es = val;
+            }
+            Register::CS => {
+                self.cs = val;
+            }
+            Register::SS => {
+                self.ss = val;
+            }
+            Register::DS => {
+                self.ds = val;
+            }
         }
     }
 }
+impl Default for CPURegisters {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 impl Cpu {
-    pub fn new() -> Self {
-        Cpu { registers: CPURegisters::new(), flags: Flags::new(), memory: [0; 1024 * 1024] }
+    pub fn new(model: CpuModel) -> Self {
+        Cpu {
+            model,
+            registers: CPURegisters::new(),
+            flags: Flags::new(),
+            memory: vec![0; 1024 * 1024].into_boxed_slice().try_into().unwrap(),
+            pending_interrupt: None,
+            segment_override: None,
+            halted: false,
+            last_interrupt: None,
+            io: PortMap::new(),
+        }
+    }
+    // Puts a bus behind every port, on top of anything mapped before
+    pub fn attach_io(&mut self, bus: Box<dyn IoBus>) {
+        self.io.map(0..=0xffff, bus);
+    }
+    // Puts a device behind a range of ports, leaving the others to what was mapped before
+    pub fn map_io(&mut self, ports: RangeInclusive<u16>, device: Box<dyn IoBus>) {
+        self.io.map(ports, device);
+    }
+    fn port_in(&mut self, port: u16, wide: bool) -> u16 {
+        if wide { self.io.read_word(port) } else { self.io.read(port) as u16 }
+    }
+    fn port_out(&mut self, port: u16, wide: bool, val: u16) {
+        if wide { self.io.write_word(port, val) } else { self.io.write(port, val as u8) }
     }
     pub fn set_ip(&mut self, address: usize) {
         self.registers.ip = address as u16;
     }
+    // 20-bit physical address of segment:offset, wrapping around at 1 MiB
+    pub fn physical_address(&self, segment: u16, offset: u16) -> usize {
+        (((segment as usize) << 4) + (offset as usize)) % self.memory.len()
+    }
+    // Asserts the interrupt line. The interrupt is taken between instructions once IF allows it.
+    pub fn raise_interrupt(&mut self, vector: u8) {
+        self.pending_interrupt = Some(vector);
+    }
+    // Called between instructions, dispatches a pending interrupt if IF is set and returns its vector
+    pub fn service_interrupts(&mut self) -> Option<u8> {
+        match self.pending_interrupt {
+            Some(vector) if self.flags.if_ => {
+                self.pending_interrupt = None;
+                self.interrupt(vector);
+                Some(vector)
+            }
+            _ => None,
+        }
+    }
+    // Pushes FLAGS, CS and IP, clears IF and TF and continues at the vector's entry in the IVT at physical 0
+    pub fn interrupt(&mut self, vector: u8) {
+        self.halted = false;
+        self.push(self.flags.to_word());
+        self.flags.if_ = false;
+        self.flags.tf = false;
+        self.push(self.registers.cs);
+        self.push(self.registers.ip);
+        (self.registers.cs, self.registers.ip) = self.interrupt_vector(vector);
+        self.last_interrupt = Some(vector);
+    }
+    // Handler address (CS, IP) in the vector table entry for `vector`
+    pub fn interrupt_vector(&self, vector: u8) -> (u16, u16) {
+        let entry = (vector as usize) * 4;
+        (self.read_memory(entry + 2, true), self.read_memory(entry, true))
+    }
+    pub fn read_memory(&self, address: usize, wide: bool) -> u16 {
+        let low_byte = self.memory[address] as u16;
+        if wide {
+            let high_byte = (self.memory[(address + 1) % self.memory.len()] as u16) << 8;
+            high_byte | low_byte
+        } else {
+            low_byte
+        }
+    }
+    pub fn write_memory(&mut self, address: usize, wide: bool, val: u16) {
+        self.memory[address] = val as u8;
+        if wide {
+            let high_address = (address + 1) % self.memory.len();
+            self.memory[high_address] = (val >> 8) as u8;
+        }
+    }
+    fn read_operand(&self, operand: &Operand, wide: bool) -> u16 {
+        match operand {
+            Operand::Register(reg) => self.registers.get(reg),
+            Operand::Memory(addr) => self.read_memory(calculate_address(addr, self), wide),
+            Operand::Immediate { value, .. } => {
+                if wide { *value as u16 } else { (*value as u16) & 0xff }
+            }
+            Operand::FarAddress { offset, .. } => *offset,
+        }
+    }
+    fn write_operand(&mut self, operand: &Operand, wide: bool, val: u16) {
+        match operand {
+            Operand::Register(reg) => self.registers.set(reg, val),
+            Operand::Memory(addr) => {
+                let address = calculate_address(addr, self);
+                self.write_memory(address, wide, val);
+            }
+            Operand::Immediate { .. } | Operand::FarAddress { .. } => { println!("Not supported") }
+        }
+    }
     pub fn execute(&mut self, instruction: &Instruction) {
+        let wide = instruction.wide;
+        let (destination, source) = (&instruction.destination, &instruction.source);
+        self.segment_override = instruction.prefixes.segment;
+        // TF as it was before the instruction decides whether it is followed by a single-step trap
+        let single_step = self.flags.tf;
         match instruction.operation {
             Operation::Mov => {
-                match (&instruction.source, &instruction.destination) {
-                    (Some(source), destination) => {
-                        match (source, destination) {
-                            (Operand::Register(src), Operand::Register(dest)) => {
-                                self.registers.set(&dest, self.registers.get(&src));
-                            }
-                            (Operand::Immediate(val), Operand::Register(reg)) => {
-                                self.registers.set(&reg, *val as u16);
-                            }
-                            (Operand::Register(src_reg), Operand::Memory(addr)) => {
-                                let dest_address = calculate_address(&addr, &self);
-                                match *src_reg {
-                                    | Register::AL
-                                    | Register::AH
-                                    | Register::BL
-                                    | Register::BH
-                                    | Register::CL
-                                    | Register::CH
-                                    | Register::DL
-                                    | Register::DH => {
-                                        let val = self.registers.get(&src_reg);
-                                        self.memory[dest_address] = val as u8;
-                                    }
-                                    _ => {
-                                        let val = self.registers.get(&src_reg);
-                                        self.memory[dest_address] = val as u8;
-                                        self.memory[dest_address + 1] = (val >> 8) as u8;
-                                    }
-                                };
-                            }
-                            (Operand::Memory(addr), Operand::Register(dest_reg)) => {
-                                let src_address = calculate_address(&addr, &self);
-                                match *dest_reg {
-                                    | Register::AL
-                                    | Register::AH
-                                    | Register::BL
-                                    | Register::BH
-                                    | Register::CL
-                                    | Register::CH
-                                    | Register::DL
-                                    | Register::DH => {
-                                        let val = self.memory[src_address];
-                                        self.registers.set(&dest_reg, val as u16);
-                                    }
-                                    _ => {
-                                        let low_byte = self.memory[src_address] as u16;
-                                        let high_byte = (self.memory[src_address + 1] as u16) << 8;
-                                        let val = high_byte + low_byte;
-                                        self.registers.set(&dest_reg, val);
-                                    }
-                                }
-                            }
-                            (Operand::Immediate(val), Operand::Memory(addr)) => {
-                                let dest_address = calculate_address(&addr, &self);
-                                if ((*val as u16) >> 8) == 0 {
-                                    self.memory[dest_address as usize] = *val as u8;
-                                } else {
-                                    let low_byte = *val as u8;
-                                    let high_byte = (*val << 8) as u8;
-                                    self.memory[dest_address as usize] = low_byte;
-                                    self.memory[(dest_address + 1) as usize] = high_byte;
-                                }
-                            }
-                            (_, _) => { println!("Not supported") }
+                match (destination, source) {
+                    (Some(destination), Some(source)) => {
+                        let val = self.read_operand(source, wide);
+                        self.write_operand(destination, wide, val);
+                    }
+                    _ => { println!("Not supported") }
+                }
+            }
+            Operation::Lea | Operation::Lds | Operation::Les => {
+                match (destination, source) {
+                    (Some(destination), Some(Operand::Memory(addr))) => {
+                        if let Operation::Lea = instruction.operation {
+                            // Only the offset is computed, memory is not read
+                            let offset = effective_address(addr, &self.registers);
+                            self.write_operand(destination, true, offset);
+                        } else {
+                            let address = calculate_address(addr, self);
+                            let offset = self.read_memory(address, true);
+                            let segment = self.read_memory((address + 2) % self.memory.len(), true);
+                            self.write_operand(destination, true, offset);
+                            let segment_register = if let Operation::Lds = instruction.operation {
+                                Register::DS
+                            } else {
+                                Register::ES
+                            };
+                            self.registers.set(&segment_register, segment);
                         }
                     }
-                    (_, _) => { println!("Not supported") }
+                    _ => { println!("Not supported") }
                 }
             }
-            Operation::Add => {
-                match (&instruction.source, &instruction.destination) {
-                    (Some(source), destination) => {
-                        match (source, destination) {
-                            (Operand::Register(src), Operand::Register(dest)) => {
-                                let src_val = self.registers.get(&src);
-                                let dest_val = self.registers.get(&dest);
-                                let (result, overflowed) = dest_val.overflowing_add(src_val);
-
-                                self.registers.set(&dest, result);
-                                if overflowed {
-                                    self.flags.set(Flag::OF);
-                                }
-                            }
-                            (Operand::Immediate(val), Operand::Register(reg)) => {
-                                let reg_val = self.registers.get(&reg);
-
-                                let (result, overflowed) = reg_val.overflowing_add(*val as u16);
-
-                                self.registers.set(&reg, result);
-                                if overflowed {
-                                    self.flags.set(Flag::OF);
-                                } else {
-                                    self.flags.unset(Flag::OF);
-                                }
-                            }
+            Operation::Xchg => {
+                match (destination, source) {
+                    (Some(destination), Some(source)) => {
+                        let dest_val = self.read_operand(destination, wide);
+                        let src_val = self.read_operand(source, wide);
+                        self.write_operand(destination, wide, src_val);
+                        self.write_operand(source, wide, dest_val);
+                    }
+                    _ => { println!("Not supported") }
+                }
+            }
+            Operation::Xlat => {
+                let segment = self.registers.get(&self.segment_override.unwrap_or(Register::DS));
+                let offset = self.registers.get(&Register::BX).wrapping_add(self.registers.get(&Register::AL));
+                let val = self.read_memory(self.physical_address(segment, offset), false);
+                self.registers.set(&Register::AL, val);
+            }
+            Operation::Cbw => {
+                let al = self.registers.get(&Register::AL);
+                self.registers.set(&Register::AX, al as u8 as i8 as i16 as u16);
+            }
+            Operation::Cwd => {
+                let negative = self.registers.get(&Register::AX) & 0x8000 != 0;
+                self.registers.set(&Register::DX, if negative { 0xffff } else { 0 });
+            }
+            Operation::Nop | Operation::Wait => {}
+            Operation::Clc => self.flags.unset(Flag::CF),
+            Operation::Stc => self.flags.set(Flag::CF),
+            Operation::Cmc => {
+                self.flags.cf = !self.flags.cf;
+            }
+            Operation::Cld => self.flags.unset(Flag::DF),
+            Operation::Std => self.flags.set(Flag::DF),
+            Operation::Cli => self.flags.unset(Flag::IF),
+            Operation::Sti => self.flags.set(Flag::IF),
+            Operation::Lahf => {
+                let low_byte = self.flags.to_word() & 0xff;
+                self.registers.set(&Register::AH, low_byte);
+            }
+            Operation::Sahf => {
+                // Only the low byte of FLAGS is replaced
+                let ah = self.registers.get(&Register::AH);
+                self.flags = Flags::from_word((self.flags.to_word() & 0xff00) | ah);
+            }
+            Operation::Daa | Operation::Das => {
+                let al = self.registers.get(&Register::AL) as u8;
+                let result = if let Operation::Daa = instruction.operation {
+                    alu::daa(&mut self.flags, al)
+                } else {
+                    alu::das(&mut self.flags, al)
+                };
+                self.registers.set(&Register::AL, result as u16);
+            }
+            Operation::Aaa | Operation::Aas => {
+                let ax = self.registers.get(&Register::AX);
+                let subtract = matches!(instruction.operation, Operation::Aas);
+                let result = alu::ascii_adjust(&mut self.flags, ax, subtract);
+                self.registers.set(&Register::AX, result);
+            }
+            Operation::Aam | Operation::Aad => {
+                let base = destination.as_ref().map_or(10, |operand| self.read_operand(operand, false) as u8);
+                let ax = self.registers.get(&Register::AX);
+                if let Operation::Aad = instruction.operation {
+                    let result = alu::aad(&mut self.flags, ax, base);
+                    self.registers.set(&Register::AX, result);
+                } else {
+                    match alu::aam(&mut self.flags, ax as u8, base) {
+                        Some(result) => self.registers.set(&Register::AX, result),
+                        // Divide error, type 0
+                        None => self.interrupt(0),
+                    }
+                }
+            }
+            Operation::Hlt => {
+                self.halted = true;
+            }
+            | Operation::Add
+            | Operation::Adc
+            | Operation::Sub
+            | Operation::Sbb
+            | Operation::Cmp
+            | Operation::And
+            | Operation::Or
+            | Operation::Xor
+            | Operation::Test => {
+                match (destination, source) {
+                    (Some(destination), Some(source)) => {
+                        let dest_val = self.read_operand(destination, wide);
+                        let src_val = self.read_operand(source, wide);
+                        let flags = &mut self.flags;
+                        let result = match instruction.operation {
+                            Operation::Add => alu::add(flags, dest_val, src_val, wide),
+                            Operation::Adc => alu::add_with_carry(flags, dest_val, src_val, flags.cf, wide),
+                            Operation::Sbb => alu::sub_with_borrow(flags, dest_val, src_val, flags.cf, wide),
+                            Operation::Sub | Operation::Cmp => alu::sub(flags, dest_val, src_val, wide),
+                            Operation::And | Operation::Test => alu::and(flags, dest_val, src_val, wide),
+                            Operation::Or => alu::or(flags, dest_val, src_val, wide),
+                            _ => alu::xor(flags, dest_val, src_val, wide),
+                        };
 
-                            (_, _) => { println!("Not supported") }
+                        // CMP and TEST only set flags, the destination is left untouched
+                        if !matches!(instruction.operation, Operation::Cmp | Operation::Test) {
+                            self.write_operand(destination, wide, result);
+                        }
+                    }
+                    _ => { println!("Not supported") }
+                }
+            }
+            Operation::Inc | Operation::Dec | Operation::Neg => {
+                match destination {
+                    Some(destination) => {
+                        let val = self.read_operand(destination, wide);
+                        let flags = &mut self.flags;
+                        let result = match instruction.operation {
+                            Operation::Inc => alu::inc(flags, val, wide),
+                            Operation::Dec => alu::dec(flags, val, wide),
+                            _ => alu::neg(flags, val, wide),
+                        };
+                        self.write_operand(destination, wide, result);
+                    }
+                    None => { println!("Not supported") }
+                }
+            }
+            Operation::Mul | Operation::Imul => {
+                match (destination, source, &instruction.third) {
+                    // 80186 IMUL reg, r/m, imm keeps only the low word of the product
+                    (Some(destination), Some(source), Some(immediate)) => {
+                        let src_val = self.read_operand(source, true);
+                        let imm_val = self.read_operand(immediate, true);
+                        let product = alu::imul(&mut self.flags, src_val, imm_val, true);
+                        self.write_operand(destination, true, product as u16);
+                    }
+                    (Some(operand), _, _) => {
+                        let src_val = self.read_operand(operand, wide);
+                        let acc = if wide { Register::AX } else { Register::AL };
+                        let acc_val = self.registers.get(&acc);
+                        let product = match instruction.operation {
+                            Operation::Mul => alu::mul(&mut self.flags, acc_val, src_val, wide),
+                            _ => alu::imul(&mut self.flags, acc_val, src_val, wide),
+                        };
+                        if wide {
+                            self.registers.set(&Register::DX, (product >> 16) as u16);
                         }
+                        self.registers.set(&Register::AX, product as u16);
                     }
-                    (_, _) => { println!("Not supported") }
+                    _ => { println!("Not supported") }
                 }
             }
-            Operation::Sub => {
-                match (&instruction.source, &instruction.destination) {
-                    (Some(source), destination) => {
-                        match (source, destination) {
-                            (Operand::Register(src), Operand::Register(dest)) => {
-                                let src_val = self.registers.get(&src);
-                                let dest_val = self.registers.get(&dest);
-                                let (result, is_negative) = dest_val.overflowing_sub(src_val);
-                                match (result, is_negative) {
-                                    (0, _) => {
-                                        self.flags.set(Flag::ZF);
-                                        self.flags.unset(Flag::SF);
-                                    }
-                                    (_, true) => {
-                                        self.flags.unset(Flag::ZF);
-                                        self.flags.set(Flag::SF);
-                                    }
-                                    (_, _) => {}
-                                }
-
-                                self.registers.set(&dest, result);
+            Operation::Div | Operation::Idiv => {
+                match destination {
+                    Some(operand) => {
+                        let divisor = self.read_operand(operand, wide);
+                        let dividend = if wide {
+                            ((self.registers.get(&Register::DX) as u32) << 16) |
+                                (self.registers.get(&Register::AX) as u32)
+                        } else {
+                            self.registers.get(&Register::AX) as u32
+                        };
+                        let result = match instruction.operation {
+                            Operation::Div => alu::div(dividend, divisor, wide),
+                            _ => alu::idiv(dividend, divisor, wide),
+                        };
+                        match (result, wide) {
+                            (Some((quotient, remainder)), true) => {
+                                self.registers.set(&Register::AX, quotient);
+                                self.registers.set(&Register::DX, remainder);
                             }
-                            (Operand::Immediate(val), Operand::Register(reg)) => {
-                                let reg_val = self.registers.get(&reg);
-
-                                let (result, is_negative) = reg_val.overflowing_sub(*val as u16);
-                                match (result, is_negative) {
-                                    (0, _) => {
-                                        self.flags.set(Flag::ZF);
-                                        self.flags.unset(Flag::SF);
-                                    }
-                                    (_, true) => {
-                                        self.flags.set(Flag::SF);
-                                        self.flags.unset(Flag::ZF);
-                                    }
-                                    (_, _) => {}
-                                }
-                                self.registers.set(&reg, result);
+                            (Some((quotient, remainder)), false) => {
+                                self.registers.set(&Register::AL, quotient);
+                                self.registers.set(&Register::AH, remainder);
                             }
-
-                            (_, _) => { println!("Not supported") }
+                            // Divide error, type 0
+                            (None, _) => self.interrupt(0),
                         }
                     }
-                    (_, _) => { println!("Not supported") }
+                    None => { println!("Not supported") }
+                }
+            }
+            | Operation::Rol
+            | Operation::Ror
+            | Operation::Rcl
+            | Operation::Rcr
+            | Operation::Shl
+            | Operation::Shr
+            | Operation::Sar => {
+                match (destination, source) {
+                    (Some(destination), Some(source)) => {
+                        let count = self.read_operand(source, false) as u8;
+                        // The 80186 only uses the low 5 bits of the count
+                        let count = if self.model.is_80186() { count & 0x1f } else { count };
+                        let val = self.read_operand(destination, wide);
+                        let operation = &instruction.operation;
+                        let result = alu::shift(&mut self.flags, operation, val, count, wide);
+                        self.write_operand(destination, wide, result);
+                    }
+                    _ => { println!("Not supported") }
+                }
+            }
+            Operation::Not => {
+                match destination {
+                    Some(destination) => {
+                        let val = self.read_operand(destination, wide);
+                        self.write_operand(destination, wide, !val);
+                    }
+                    None => { println!("Not supported") }
+                }
+            }
+            Operation::Push => {
+                match destination {
+                    Some(operand) => {
+                        // SP is decremented before the operand is read, so PUSH SP pushes the new SP
+                        self.registers.sp = self.registers.sp.wrapping_sub(2);
+                        let val = self.read_operand(operand, true);
+                        let address = self.stack_address();
+                        self.write_memory(address, true, val);
+                    }
+                    None => { println!("Not supported") }
+                }
+            }
+            Operation::Pop => {
+                match destination {
+                    Some(operand) => {
+                        let val = self.pop();
+                        self.write_operand(operand, true, val);
+                    }
+                    None => { println!("Not supported") }
+                }
+            }
+            Operation::Pusha => {
+                // SP is pushed as it was before the first push
+                let sp = self.registers.sp;
+                for register in [Register::AX, Register::CX, Register::DX, Register::BX] {
+                    self.push(self.registers.get(&register));
+                }
+                self.push(sp);
+                for register in [Register::BP, Register::SI, Register::DI] {
+                    self.push(self.registers.get(&register));
+                }
+            }
+            Operation::Popa => {
+                // The saved SP is discarded
+                for register in [Register::DI, Register::SI, Register::BP] {
+                    let val = self.pop();
+                    self.registers.set(&register, val);
+                }
+                self.pop();
+                for register in [Register::BX, Register::DX, Register::CX, Register::AX] {
+                    let val = self.pop();
+                    self.registers.set(&register, val);
                 }
             }
-            Operation::Cmp => {
-                match (&instruction.source, &instruction.destination) {
-                    (Some(source), destination) => {
-                        match (source, destination) {
-                            (Operand::Register(src), Operand::Register(dest)) => {
-                                let src_val = self.registers.get(&src);
-                                let dest_val = self.registers.get(&dest);
-                                let (result, is_negative) = dest_val.overflowing_sub(src_val);
-                                match (result, is_negative) {
-                                    (0, _) => {
-                                        self.flags.set(Flag::ZF);
-                                        self.flags.unset(Flag::SF);
-                                    }
-                                    (_, true) => {
-                                        self.flags.set(Flag::SF);
-                                        self.flags.unset(Flag::ZF);
-                                    }
-                                    (_, _) => {}
-                                }
+            Operation::Enter => {
+                match (destination, source) {
+                    (Some(size), Some(level)) => {
+                        let size = self.read_operand(size, true);
+                        let level = self.read_operand(level, true) & 0x1f;
+                        self.push(self.registers.bp);
+                        let frame = self.registers.sp;
+                        if level > 0 {
+                            // Copy the frame pointers of the enclosing levels, then add the new one
+                            let mut bp = self.registers.bp;
+                            for _ in 1..level {
+                                bp = bp.wrapping_sub(2);
+                                let val = self.read_memory(self.physical_address(self.registers.ss, bp), true);
+                                self.push(val);
                             }
-                            (Operand::Immediate(val), Operand::Register(reg)) => {
-                                let reg_val = self.registers.get(&reg);
-
-                                let (result, is_negative) = reg_val.overflowing_sub(*val as u16);
-                                match (result, is_negative) {
-                                    (0, _) => {
-                                        self.flags.set(Flag::ZF);
-                                    }
-                                    (_, true) => {
-                                        self.flags.set(Flag::SF);
-                                    }
-                                    (_, _) => {}
-                                }
+                            self.push(frame);
+                        }
+                        self.registers.bp = frame;
+                        self.registers.sp = self.registers.sp.wrapping_sub(size);
+                    }
+                    _ => { println!("Not supported") }
+                }
+            }
+            Operation::Leave => {
+                self.registers.sp = self.registers.bp;
+                self.registers.bp = self.pop();
+            }
+            Operation::Bound => {
+                match (destination, source) {
+                    (Some(destination), Some(Operand::Memory(addr))) => {
+                        let index = self.read_operand(destination, true) as i16;
+                        let address = calculate_address(addr, self);
+                        let lower = self.read_memory(address, true) as i16;
+                        let upper = self.read_memory((address + 2) % self.memory.len(), true) as i16;
+                        if index < lower || index > upper {
+                            // Type 5 is a fault, the saved IP points back at the BOUND itself
+                            let length = instruction.bytes.len() as u16;
+                            self.registers.ip = self.registers.ip.wrapping_sub(length);
+                            self.interrupt(5);
+                        }
+                    }
+                    _ => { println!("Not supported") }
+                }
+            }
+            Operation::Pushf => {
+                self.push(self.flags.to_word());
+            }
+            Operation::Popf => {
+                let word = self.pop();
+                self.flags = Flags::from_word(word);
+            }
+            Operation::Jmp | Operation::Call => {
+                // Relative targets are already resolved by the decoder, indirect ones are read
+                let target = match (instruction.jump_target(), destination) {
+                    (Some(target), _) => Some(target),
+                    (None, Some(operand)) => Some(self.read_operand(operand, true)),
+                    (None, None) => None,
+                };
+                match target {
+                    Some(target) => {
+                        if let Operation::Call = instruction.operation {
+                            self.push(self.registers.ip);
+                        }
+                        self.registers.ip = target;
+                    }
+                    None => { println!("Not supported") }
+                }
+            }
+            Operation::JmpFar | Operation::CallFar => {
+                match destination.as_ref().and_then(|operand| self.read_far_pointer(operand)) {
+                    Some((segment, offset)) => {
+                        if let Operation::CallFar = instruction.operation {
+                            self.push(self.registers.cs);
+                            self.push(self.registers.ip);
+                        }
+                        self.registers.cs = segment;
+                        self.registers.ip = offset;
+                    }
+                    None => { println!("Not supported") }
+                }
+            }
+            Operation::In | Operation::Out => {
+                match (&instruction.operation, destination, source) {
+                    (Operation::In, Some(destination), Some(port)) => {
+                        let port = self.read_operand(port, true);
+                        let val = self.port_in(port, wide);
+                        self.write_operand(destination, wide, val);
+                    }
+                    (Operation::Out, Some(port), Some(source)) => {
+                        let port = self.read_operand(port, true);
+                        let val = self.read_operand(source, wide);
+                        self.port_out(port, wide, val);
+                    }
+                    _ => { println!("Not supported") }
+                }
+            }
+            Operation::Int => {
+                match destination {
+                    Some(operand) => {
+                        let vector = self.read_operand(operand, false) as u8;
+                        self.interrupt(vector);
+                    }
+                    None => { println!("Not supported") }
+                }
+            }
+            Operation::Int3 => self.interrupt(3),
+            Operation::Into => {
+                if self.flags.of {
+                    self.interrupt(4);
+                }
+            }
+            Operation::Iret => {
+                self.registers.ip = self.pop();
+                self.registers.cs = self.pop();
+                let flags = self.pop();
+                self.flags = Flags::from_word(flags);
+            }
+            | Operation::Movs
+            | Operation::Cmps
+            | Operation::Scas
+            | Operation::Lods
+            | Operation::Stos
+            | Operation::Ins
+            | Operation::Outs => {
+                match instruction.prefixes.repeat {
+                    None => self.string_step(&instruction.operation, wide),
+                    Some(repeat) => {
+                        // Repeat until CX runs out, CMPS and SCAS also stop on the first (mis)match
+                        let compares = matches!(instruction.operation, Operation::Cmps | Operation::Scas);
+                        while self.registers.get(&Register::CX) != 0 {
+                            self.string_step(&instruction.operation, wide);
+                            let cx = self.registers.get(&Register::CX).wrapping_sub(1);
+                            self.registers.set(&Register::CX, cx);
+                            if compares && self.flags.zf != (repeat == Repeat::Rep) {
+                                break;
                             }
-
-                            (_, _) => { println!("Not supported") }
                         }
                     }
-                    (_, _) => { println!("Not supported") }
                 }
             }
-            Operation::Jnz => {
-                match instruction.destination {
-                    Operand::Immediate(val) => {
-                        if !self.flags.zf {
-                            self.set_ip(val as usize);
+            Operation::Ret | Operation::Retf => {
+                self.registers.ip = self.pop();
+                if let Operation::Retf = instruction.operation {
+                    self.registers.cs = self.pop();
+                }
+                // RET imm16 releases that many bytes of parameters from the stack
+                if let Some(operand) = destination {
+                    let release = self.read_operand(operand, true);
+                    self.registers.sp = self.registers.sp.wrapping_add(release);
+                }
+            }
+            | Operation::Jnz
+            | Operation::Jz
+            | Operation::Jl
+            | Operation::Jle
+            | Operation::Jb
+            | Operation::Jbe
+            | Operation::Jp
+            | Operation::Jo
+            | Operation::Js
+            | Operation::Jne
+            | Operation::Jnl
+            | Operation::Jg
+            | Operation::Jnb
+            | Operation::Ja
+            | Operation::Jnp
+            | Operation::Jno
+            | Operation::Jns
+            | Operation::Loop
+            | Operation::Loopz
+            | Operation::Loopnz
+            | Operation::Jcxz => {
+                match instruction.jump_target() {
+                    Some(target) => {
+                        if self.jump_condition(&instruction.operation) {
+                            self.set_ip(target as usize);
                         }
                     }
-                    _ => {
+                    None => {
                         println!("not supproted as of now");
                     }
                 }
             }
+        }
+        if single_step {
+            self.interrupt(1);
+        }
+    }
+    // One iteration of a string instruction. The source is DS:SI (segment can be overridden), the destination ES:DI.
+    fn string_step(&mut self, operation: &Operation, wide: bool) {
+        let (si, di) = (self.registers.si, self.registers.di);
+        let source = self.physical_address(
+            self.registers.get(&self.segment_override.unwrap_or(Register::DS)),
+            si
+        );
+        let destination = self.physical_address(self.registers.es, di);
+        let accumulator = if wide { Register::AX } else { Register::AL };
+        let (uses_si, uses_di) = match operation {
+            Operation::Movs => {
+                let val = self.read_memory(source, wide);
+                self.write_memory(destination, wide, val);
+                (true, true)
+            }
+            Operation::Cmps => {
+                let (src_val, dest_val) = (self.read_memory(source, wide), self.read_memory(destination, wide));
+                alu::sub(&mut self.flags, src_val, dest_val, wide);
+                (true, true)
+            }
+            Operation::Scas => {
+                let (acc_val, dest_val) = (self.registers.get(&accumulator), self.read_memory(destination, wide));
+                alu::sub(&mut self.flags, acc_val, dest_val, wide);
+                (false, true)
+            }
+            Operation::Lods => {
+                let val = self.read_memory(source, wide);
+                self.registers.set(&accumulator, val);
+                (true, false)
+            }
+            Operation::Ins => {
+                let val = self.port_in(self.registers.get(&Register::DX), wide);
+                self.write_memory(destination, wide, val);
+                (false, true)
+            }
+            Operation::Outs => {
+                let val = self.read_memory(source, wide);
+                self.port_out(self.registers.get(&Register::DX), wide, val);
+                (true, false)
+            }
             _ => {
-                println!("Not supported as of now");
+                let val = self.registers.get(&accumulator);
+                self.write_memory(destination, wide, val);
+                (false, true)
+            }
+        };
+
+        let step: u16 = if wide { 2 } else { 1 };
+        let step = if self.flags.df { step.wrapping_neg() } else { step };
+        if uses_si {
+            self.registers.si = si.wrapping_add(step);
+        }
+        if uses_di {
+            self.registers.di = di.wrapping_add(step);
+        }
+    }
+    // Segment and offset of a far JMP or CALL target, either immediate or a 32-bit pointer in memory
+    fn read_far_pointer(&self, operand: &Operand) -> Option<(u16, u16)> {
+        match operand {
+            Operand::FarAddress { segment, offset } => Some((*segment, *offset)),
+            Operand::Memory(addr) => {
+                let address = calculate_address(addr, self);
+                let offset = self.read_memory(address, true);
+                let segment = self.read_memory((address + 2) % self.memory.len(), true);
+                Some((segment, offset))
             }
+            _ => None,
+        }
+    }
+    // Physical address of the top of the stack, SS:SP
+    fn stack_address(&self) -> usize {
+        self.physical_address(self.registers.ss, self.registers.sp)
+    }
+    pub fn push(&mut self, val: u16) {
+        self.registers.sp = self.registers.sp.wrapping_sub(2);
+        let address = self.stack_address();
+        self.write_memory(address, true, val);
+    }
+    pub fn pop(&mut self) -> u16 {
+        let address = self.stack_address();
+        let val = self.read_memory(address, true);
+        self.registers.sp = self.registers.sp.wrapping_add(2);
+        val
+    }
+    // Evaluates the condition of a conditional jump or loop. LOOP* decrements CX first.
+    fn jump_condition(&mut self, operation: &Operation) -> bool {
+        let cx = self.registers.get(&Register::CX);
+        if let Operation::Loop | Operation::Loopz | Operation::Loopnz = operation {
+            self.registers.set(&Register::CX, cx.wrapping_sub(1));
         }
+        branch_taken(operation, &self.flags, cx)
+    }
+}
+
+// Memory and the I/O bus are left out, they are too big or opaque to be useful here
+impl fmt::Debug for Cpu {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Cpu")
+            .field("model", &self.model)
+            .field("registers", &self.registers)
+            .field("flags", &self.flags)
+            .field("pending_interrupt", &self.pending_interrupt)
+            .field("segment_override", &self.segment_override)
+            .field("halted", &self.halted)
+            .field("last_interrupt", &self.last_interrupt)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for Cpu {
+    fn default() -> Self {
+        Self::new(CpuModel::default())
     }
 }
+
+// Physical address of a memory operand, in the override segment if there is one
 fn calculate_address(address: &AddressingMode, cpu: &Cpu) -> usize {
-    let addr = match address {
-        AddressingMode::Direct(address) => { *address as u16 }
-        AddressingMode::Register(reg) => {
-            let address = cpu.registers.get(&reg);
-            address
-        }
+    let segment = cpu.segment_override.unwrap_or_else(|| default_segment(address));
+    let segment = cpu.registers.get(&segment);
+    cpu.physical_address(segment, effective_address(address, &cpu.registers))
+}
+
+// The 16-bit offset of a memory operand within its segment
+pub fn effective_address(address: &AddressingMode, registers: &CPURegisters) -> u16 {
+    match address {
+        AddressingMode::Direct(address) => *address as u16,
+        AddressingMode::Register(reg) => registers.get(reg),
         AddressingMode::Memory { base, index, displacement } => {
-            let addr: u16 = match (base, index, displacement) {
-                (Some(base_reg), Some(index_reg), Some(disp)) => {
-                    let address =
-                        cpu.registers.get(&base_reg) +
-                        cpu.registers.get(&index_reg) +
-                        (*disp as u16);
-                    address
-                }
-                (None, Some(index_reg), Some(disp)) => {
-                    let address = cpu.registers.get(&index_reg) + (*disp as u16);
-                    address
-                }
-                (Some(base_reg), None, Some(disp)) => {
-                    let address = cpu.registers.get(&base_reg) + (*disp as u16);
-                    address
-                }
-                (Some(base_reg), Some(index_reg), None) => {
-                    let address = cpu.registers.get(&base_reg) + cpu.registers.get(&index_reg);
-                    address
-                }
-                (_, _, _) => { 0 }
-            };
-            addr
+            let base_val = base.as_ref().map_or(0, |reg| registers.get(reg));
+            let index_val = index.as_ref().map_or(0, |reg| registers.get(reg));
+            let disp_val = displacement.map_or(0, |disp| disp.value as u16);
+            base_val.wrapping_add(index_val).wrapping_add(disp_val)
+        }
+    }
+}
+
+// Whether a conditional jump or loop is taken, given the flags and CX before it executes
+pub fn branch_taken(operation: &Operation, flags: &Flags, cx: u16) -> bool {
+    match operation {
+        Operation::Jo => flags.of,
+        Operation::Jno => !flags.of,
+        Operation::Jb => flags.cf,
+        Operation::Jnb => !flags.cf,
+        Operation::Jz => flags.zf,
+        Operation::Jnz | Operation::Jne => !flags.zf,
+        Operation::Jbe => flags.cf || flags.zf,
+        Operation::Ja => !flags.cf && !flags.zf,
+        Operation::Js => flags.sf,
+        Operation::Jns => !flags.sf,
+        Operation::Jp => flags.pf,
+        Operation::Jnp => !flags.pf,
+        Operation::Jl => flags.sf != flags.of,
+        Operation::Jnl => flags.sf == flags.of,
+        Operation::Jle => flags.zf || flags.sf != flags.of,
+        Operation::Jg => !flags.zf && flags.sf == flags.of,
+        // LOOP* test CX after decrementing it
+        Operation::Loop => cx != 1,
+        Operation::Loopz => cx != 1 && flags.zf,
+        Operation::Loopnz => cx != 1 && !flags.zf,
+        Operation::Jcxz => cx == 0,
+        _ => false,
+    }
+}
+
+// BP-based addresses are relative to the stack segment, everything else to the data segment
+fn default_segment(address: &AddressingMode) -> Register {
+    match address {
+        AddressingMode::Memory { base: Some(Register::BP), .. } => Register::SS,
+        _ => Register::DS,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::decoder::decode;
+
+    // Programs run from here, clear of the vector table at physical 0
+    const CODE_SEGMENT: u16 = 0x100;
+
+    // Loads a program at CODE_SEGMENT:0, lets `setup` prepare the CPU and runs until HLT or IP leaves the program
+    fn run(program: &[u8], setup: impl FnOnce(&mut Cpu)) -> Cpu {
+        run_on(CpuModel::I8086, program, setup)
+    }
+
+    fn run_on(model: CpuModel, program: &[u8], setup: impl FnOnce(&mut Cpu)) -> Cpu {
+        let mut cpu = Cpu::new(model);
+        cpu.registers.cs = CODE_SEGMENT;
+        let start = cpu.physical_address(CODE_SEGMENT, 0);
+        cpu.memory[start..start + program.len()].copy_from_slice(program);
+        setup(&mut cpu);
+        while !cpu.halted && (cpu.registers.ip as usize) < program.len() {
+            let instruction = decode(&mut cpu).unwrap();
+            cpu.execute(&instruction);
+        }
+        cpu
+    }
+
+    // Points an interrupt vector at CODE_SEGMENT:ip
+    fn set_vector(cpu: &mut Cpu, vector: u8, ip: u16) {
+        let entry = vector as usize * 4;
+        cpu.write_memory(entry, true, ip);
+        cpu.write_memory(entry + 2, true, CODE_SEGMENT);
+    }
+
+    #[test]
+    fn word_mul_and_div_use_dx_ax() {
+        // mov ax, 0x1234; mov bx, 0x100; mul bx; mov cx, 0x100; div cx
+        let program = [0xb8, 0x34, 0x12, 0xbb, 0x00, 0x01, 0xf7, 0xe3, 0xb9, 0x00, 0x01, 0xf7, 0xf1];
+        let cpu = run(&program, |_| {});
+        assert_eq!(cpu.registers.get(&Register::AX), 0x1234);
+        assert_eq!(cpu.registers.get(&Register::DX), 0x0000);
+    }
+
+    #[test]
+    fn divide_error_takes_interrupt_0() {
+        // mov ax, 5; mov bl, 0; div bl; nop; hlt
+        let program = [0xb8, 0x05, 0x00, 0xb3, 0x00, 0xf6, 0xf3, 0x90, 0xf4];
+        let cpu = run(&program, |cpu| set_vector(cpu, 0, 8));
+        assert!(cpu.halted);
+        assert_eq!(cpu.registers.ip, 9);
+        // AX is left alone and the 8086 returns to the instruction after the DIV
+        assert_eq!(cpu.registers.get(&Register::AX), 5);
+        assert_eq!(cpu.registers.sp, 0xfffa);
+        assert_eq!(cpu.read_memory(cpu.stack_address(), true), 7);
+    }
+
+    #[test]
+    fn push_and_pop_use_ss_sp() {
+        // mov ax, 0x1234; push ax; push sp; pop bx; pop cx; push ax; mov bp, 0x10; pop word [bp]
+        let program = [0xb8, 0x34, 0x12, 0x50, 0x54, 0x5b, 0x59, 0x50, 0xbd, 0x10, 0x00, 0x8f, 0x46, 0x00];
+        let cpu = run(&program, |cpu| {
+            cpu.registers.ss = 0x200;
+            cpu.registers.sp = 0x100;
+        });
+        assert_eq!(cpu.registers.sp, 0x100);
+        // The 8086 pushes SP after decrementing it
+        assert_eq!(cpu.registers.get(&Register::BX), 0xfc);
+        assert_eq!(cpu.registers.get(&Register::CX), 0x1234);
+        assert_eq!(cpu.read_memory(0x20fe, true), 0x1234);
+        // BP-based addresses are in the stack segment
+        assert_eq!(cpu.read_memory(0x2010, true), 0x1234);
+    }
+
+    #[test]
+    fn pushf_and_popf_round_trip_the_flags_word() {
+        // stc; pushf; pop dx; mov ax, 0x0840; push ax; popf
+        let program = [0xf9, 0x9c, 0x5a, 0xb8, 0x40, 0x08, 0x50, 0x9d];
+        let cpu = run(&program, |_| {});
+        // Bits 1 and 12-15 always read as set
+        assert_eq!(cpu.registers.get(&Register::DX), 0xf003);
+        assert_eq!(cpu.flags.to_string(), "ZO");
+        assert_eq!(cpu.registers.sp, 0);
+    }
+
+    #[test]
+    fn call_and_ret_in_every_encoding() {
+        let program = [
+            0xe8, 0x0a, 0x00, // call 0x0d
+            0x50, // push ax
+            0x9a, 0x11, 0x00, 0x00, 0x01, // call 0x100:0x11
+            0xff, 0xd6, // call si
+            0xf4, // hlt
+            0x90,
+            0xb9, 0x02, 0x00, // 0x0d: mov cx, 2
+            0xc3, // ret
+            0xba, 0x03, 0x00, // 0x11: mov dx, 3
+            0xca, 0x02, 0x00, // retf 2, dropping the pushed AX
+            0x90,
+            0xbf, 0x04, 0x00, // 0x18: mov di, 4
+            0xc3, // ret
+        ];
+        let cpu = run(&program, |cpu| cpu.registers.set(&Register::SI, 0x18));
+        assert!(cpu.halted);
+        assert_eq!(cpu.registers.ip, 0x0c);
+        assert_eq!(cpu.registers.cs, CODE_SEGMENT);
+        assert_eq!(cpu.registers.sp, 0);
+        assert_eq!(cpu.registers.get(&Register::CX), 2);
+        assert_eq!(cpu.registers.get(&Register::DX), 3);
+        assert_eq!(cpu.registers.get(&Register::DI), 4);
+    }
+
+    #[test]
+    fn far_jump_through_memory_reads_offset_then_segment() {
+        // jmp far [0x20], with 0x0200:0x0005 stored at DS:0x20
+        let program = [0xff, 0x2e, 0x20, 0x00];
+        let cpu = run(&program, |cpu| {
+            cpu.write_memory(0x20, true, 0x0005);
+            cpu.write_memory(0x22, true, 0x0200);
+        });
+        assert_eq!((cpu.registers.cs, cpu.registers.ip), (0x0200, 0x0005));
+    }
+
+    #[test]
+    fn physical_addresses_wrap_at_1_mib() {
+        let mut cpu = Cpu::new(CpuModel::I8086);
+        assert_eq!(cpu.physical_address(0x1234, 0x5678), 0x179b8);
+        assert_eq!(cpu.physical_address(0xffff, 0x0010), 0x00000);
+        assert_eq!(cpu.physical_address(0xffff, 0xffff), 0x0ffef);
+        // The high byte of a word at the last address comes from address 0
+        cpu.write_memory(0xfffff, true, 0xabcd);
+        assert_eq!((cpu.memory[0xfffff], cpu.memory[0]), (0xcd, 0xab));
+        assert_eq!(cpu.read_memory(0xfffff, true), 0xabcd);
+    }
+
+    #[test]
+    fn offsets_wrap_within_the_segment() {
+        // mov ax, 0x200; mov ds, ax; mov ax, [bx + si]; es: mov [bx + si], ax
+        let program = [0xb8, 0x00, 0x02, 0x8e, 0xd8, 0x8b, 0x00, 0x26, 0x89, 0x00];
+        let cpu = run(&program, |cpu| {
+            cpu.registers.set(&Register::BX, 0xffff);
+            cpu.registers.set(&Register::SI, 0x0002);
+            cpu.registers.es = 0x300;
+            cpu.write_memory(0x2001, true, 0x5a5a);
+        });
+        assert_eq!(cpu.registers.ds, 0x200);
+        assert_eq!(cpu.registers.get(&Register::AX), 0x5a5a);
+        assert_eq!(cpu.read_memory(0x3001, true), 0x5a5a);
+    }
+
+    // DS:SI at physical 0x2000 and ES:DI at 0x3000, with CX iterations
+    fn strings(cpu: &mut Cpu, source: &[u8], destination: &[u8], cx: u16) {
+        cpu.registers.ds = 0x200;
+        cpu.registers.es = 0x300;
+        cpu.registers.set(&Register::CX, cx);
+        cpu.memory[0x2000..0x2000 + source.len()].copy_from_slice(source);
+        cpu.memory[0x3000..0x3000 + destination.len()].copy_from_slice(destination);
+    }
+
+    #[test]
+    fn rep_movs_runs_cx_times_in_the_direction_of_df() {
+        let cpu = run(&[0xf3, 0xa4], |cpu| strings(cpu, b"abcd", b"", 4));
+        assert_eq!(&cpu.memory[0x3000..0x3004], b"abcd");
+        assert_eq!(cpu.registers.get(&Register::CX), 0);
+        assert_eq!((cpu.registers.si, cpu.registers.di), (4, 4));
+        // std; rep movsw, copying the two words at 2 and 0 backwards
+        let cpu = run(&[0xfd, 0xf3, 0xa5], |cpu| {
+            strings(cpu, b"abcd", b"", 2);
+            cpu.registers.si = 2;
+            cpu.registers.di = 2;
+        });
+        assert_eq!(&cpu.memory[0x3000..0x3004], b"abcd");
+        assert_eq!((cpu.registers.si, cpu.registers.di), (0xfffe, 0xfffe));
+    }
+
+    #[test]
+    fn repe_cmps_stops_at_the_first_difference() {
+        let cpu = run(&[0xf3, 0xa6], |cpu| strings(cpu, b"abxd", b"abcd", 4));
+        assert_eq!(cpu.registers.get(&Register::CX), 1);
+        assert_eq!((cpu.registers.si, cpu.registers.di), (3, 3));
+        assert!(!cpu.flags.zf);
+    }
+
+    #[test]
+    fn repne_scas_stops_at_the_first_match() {
+        let cpu = run(&[0xb0, b'c', 0xf2, 0xae], |cpu| strings(cpu, b"", b"abcd", 4));
+        assert_eq!(cpu.registers.get(&Register::CX), 1);
+        assert_eq!(cpu.registers.di, 3);
+        assert!(cpu.flags.zf);
+    }
+
+    #[test]
+    fn rep_with_cx_zero_does_nothing() {
+        let cpu = run(&[0xf3, 0xa6], |cpu| strings(cpu, b"a", b"b", 0));
+        assert_eq!((cpu.registers.si, cpu.registers.di), (0, 0));
+        assert_eq!(cpu.flags.to_string(), "");
+    }
+
+    #[test]
+    fn int_uses_a_vector_table_inside_the_program_image() {
+        let image = [
+            0xeb, 0x06, // jmp short 8, over the table
+            0x90, 0x90,
+            0x0c, 0x00, 0x00, 0x00, // vector 1: 0000:000c
+            0xcd, 0x01, // int 1
+            0xf4, // hlt
+            0x90,
+            0xbb, 0x07, 0x00, // 0x0c: mov bx, 7
+            0xcf, // iret
+        ];
+        let mut cpu = Cpu::new(CpuModel::I8086);
+        cpu.memory[..image.len()].copy_from_slice(&image);
+        while !cpu.halted {
+            let instruction = decode(&mut cpu).unwrap();
+            cpu.execute(&instruction);
+        }
+        assert_eq!(cpu.registers.get(&Register::BX), 7);
+        assert_eq!((cpu.registers.cs, cpu.registers.ip), (0, 0x0b));
+        assert_eq!(cpu.last_interrupt, Some(1));
+        assert_eq!(cpu.registers.sp, 0);
+    }
+
+    #[test]
+    fn interrupt_through_an_empty_vector_goes_to_0000_0000() {
+        let mut cpu = Cpu::new(CpuModel::I8086);
+        cpu.registers.cs = CODE_SEGMENT;
+        cpu.registers.ip = 0x10;
+        cpu.halted = true;
+        cpu.interrupt(3);
+        assert_eq!((cpu.registers.cs, cpu.registers.ip), (0, 0));
+        assert_eq!(cpu.last_interrupt, Some(3));
+        assert!(!cpu.halted);
+        assert_eq!((cpu.pop(), cpu.pop()), (0x10, CODE_SEGMENT));
+    }
+
+    #[test]
+    fn into_only_interrupts_on_overflow_and_iret_restores_flags() {
+        let program = [
+            0xce, // into, OF clear
+            0xb0, 0x7f, // mov al, 0x7f
+            0x04, 0x01, // add al, 1, setting OF
+            0xce, // into
+            0xf4, // hlt
+            0x90,
+            0xb3, 0x01, // 0x08: mov bl, 1
+            0xf8, // clc
+            0xcf, // iret
+        ];
+        let cpu = run(&program, |cpu| set_vector(cpu, 4, 0x08));
+        assert!(cpu.halted);
+        assert_eq!(cpu.registers.ip, 0x07);
+        assert_eq!(cpu.registers.get(&Register::BL), 1);
+        assert_eq!(cpu.flags.to_string(), "ASO");
+    }
+
+    // Byte registers at consecutive ports from `base`, shared with the test
+    struct Latch {
+        base: u16,
+        registers: Rc<RefCell<Vec<u8>>>,
+    }
+
+    impl IoBus for Latch {
+        fn read(&mut self, port: u16) -> u8 {
+            self.registers.borrow()[(port - self.base) as usize]
+        }
+        fn write(&mut self, port: u16, val: u8) {
+            self.registers.borrow_mut()[(port - self.base) as usize] = val;
         }
-    };
-    return addr as usize;
+    }
+
+    #[test]
+    fn in_and_out_reach_the_device_mapped_over_the_port() {
+        let program = [
+            0xb0, 0x11, // mov al, 0x11
+            0xe6, 0x60, // out 0x60, al
+            0xb8, 0x22, 0x33, // mov ax, 0x3322
+            0xe7, 0x61, // out 0x61, ax
+            0xba, 0x63, 0x00, // mov dx, 0x63
+            0xb0, 0x44, // mov al, 0x44
+            0xee, // out dx, al
+            0xba, 0x64, 0x00, // mov dx, 0x64
+            0xb8, 0x55, 0x66, // mov ax, 0x6655
+            0xef, // out dx, ax, the high byte going to unmapped 0x65
+            0xe4, 0x60, // in al, 0x60
+            0x88, 0xc3, // mov bl, al
+            0xe5, 0x62, // in ax, 0x62
+            0x89, 0xc1, // mov cx, ax
+            0xba, 0x63, 0x00, // mov dx, 0x63
+            0xed, // in ax, dx, across both devices
+            0x89, 0xc6, // mov si, ax
+            0xba, 0x64, 0x00, // mov dx, 0x64
+            0xed, // in ax, dx, the high byte from unmapped 0x65
+            0x89, 0xc7, // mov di, ax
+            0xba, 0x70, 0x00, // mov dx, 0x70
+            0xec, // in al, dx
+        ];
+        let first = Rc::new(RefCell::new(vec![0; 4]));
+        let second = Rc::new(RefCell::new(vec![0; 1]));
+        let cpu = run(&program, |cpu| {
+            cpu.map_io(0x60..=0x63, Box::new(Latch { base: 0x60, registers: first.clone() }));
+            cpu.map_io(0x64..=0x64, Box::new(Latch { base: 0x64, registers: second.clone() }));
+        });
+        assert_eq!(*first.borrow(), vec![0x11, 0x22, 0x33, 0x44]);
+        assert_eq!(*second.borrow(), vec![0x55]);
+        assert_eq!(cpu.registers.get(&Register::BL), 0x11);
+        assert_eq!(cpu.registers.get(&Register::CX), 0x4433);
+        assert_eq!(cpu.registers.get(&Register::SI), 0x5544);
+        assert_eq!(cpu.registers.get(&Register::DI), 0xff55);
+        assert_eq!(cpu.registers.get(&Register::AL), 0xff);
+    }
+
+    #[test]
+    fn without_devices_ports_read_0xff() {
+        // in ax, 0x60; mov bx, ax; out 0x60, ax; in al, 0x61
+        let cpu = run(&[0xe5, 0x60, 0x89, 0xc3, 0xe7, 0x60, 0xe4, 0x61], |_| {});
+        assert_eq!(cpu.registers.get(&Register::BX), 0xffff);
+        assert_eq!(cpu.registers.get(&Register::AL), 0xff);
+    }
+
+    #[test]
+    fn les_lds_xlat_cbw_cwd_and_xchg() {
+        let program = [
+            0xc4, 0x7f, 0x04, // les di, [bx + 4]
+            0xc5, 0x17, // lds dx, [bx]
+            0xb0, 0x02, // mov al, 2
+            0xd7, // xlat, from the new DS
+            0x98, // cbw
+            0x89, 0xc5, // mov bp, ax
+            0x99, // cwd
+            0x91, // xchg ax, cx
+        ];
+        let cpu = run(&program, |cpu| {
+            cpu.registers.ds = 0x200;
+            cpu.registers.set(&Register::BX, 0x10);
+            cpu.registers.set(&Register::CX, 0x0123);
+            cpu.write_memory(0x2010, true, 0x1234);
+            cpu.write_memory(0x2012, true, 0x0300);
+            cpu.write_memory(0x2014, true, 0x5678);
+            cpu.write_memory(0x2016, true, 0x0400);
+            cpu.memory[0x3012] = 0x99;
+        });
+        assert_eq!((cpu.registers.es, cpu.registers.get(&Register::DI)), (0x0400, 0x5678));
+        assert_eq!(cpu.registers.ds, 0x0300);
+        assert_eq!(cpu.registers.get(&Register::BP), 0xff99);
+        assert_eq!(cpu.registers.get(&Register::DX), 0xffff);
+        assert_eq!(cpu.registers.get(&Register::CX), 0xff99);
+        assert_eq!(cpu.registers.get(&Register::AX), 0x0123);
+    }
+
+    #[test]
+    fn lea_only_computes_the_offset() {
+        // lea si, [bp + di - 2], with no memory access or segment added
+        let cpu = run(&[0x8d, 0x73, 0xfe], |cpu| {
+            cpu.registers.ss = 0x1000;
+            cpu.registers.set(&Register::BP, 0x0001);
+            cpu.registers.set(&Register::DI, 0x0000);
+        });
+        assert_eq!(cpu.registers.get(&Register::SI), 0xffff);
+    }
+
+    #[test]
+    fn flag_instructions_lahf_sahf_and_hlt() {
+        let program = [
+            0xf9, 0xf5, // stc; cmc
+            0xf9, 0xfd, 0xfb, // stc; std; sti
+            0x9f, // lahf
+            0x88, 0xe3, // mov bl, ah
+            0xb4, 0xd5, // mov ah, 0xd5
+            0x9e, // sahf
+            0x90, 0x9b, // nop; wait
+            0xf4, // hlt
+            0xfa, // cli, never reached
+        ];
+        let cpu = run(&program, |_| {});
+        // Bit 1 of the flags word is always set
+        assert_eq!(cpu.registers.get(&Register::BL), 0x03);
+        assert_eq!(cpu.flags.to_string(), "CPAZSID");
+        assert!(cpu.halted);
+        assert_eq!(cpu.registers.ip, 0x0e);
+        // clc; cld; cli, from every flag but TF set
+        let cpu = run(&[0xf8, 0xfc, 0xfa], |cpu| cpu.flags = Flags::from_word(0xfeff));
+        assert_eq!(cpu.flags.to_string(), "PAZSO");
+    }
+
+    #[test]
+    fn adc_and_sbb_chain_32_bit_arithmetic() {
+        let program = [
+            0x01, 0xd8, // add ax, bx
+            0x11, 0xca, // adc dx, cx, 0x0001ffff + 0x00000001
+            0x2b, 0xc3, // sub ax, bx
+            0x1b, 0xd1, // sbb dx, cx, and back again
+        ];
+        let cpu = run(&program, |cpu| {
+            cpu.registers.set(&Register::AX, 0xffff);
+            cpu.registers.set(&Register::DX, 0x0001);
+            cpu.registers.set(&Register::BX, 0x0001);
+        });
+        assert_eq!(cpu.registers.get(&Register::AX), 0xffff);
+        assert_eq!(cpu.registers.get(&Register::DX), 0x0001);
+        assert_eq!(cpu.flags.to_string(), "");
+    }
+
+    #[test]
+    fn aam_with_base_0_takes_the_divide_error() {
+        // aam 0; nop; hlt
+        let cpu = run(&[0xd4, 0x00, 0x90, 0xf4], |cpu| {
+            cpu.registers.set(&Register::AX, 0x0042);
+            set_vector(cpu, 0, 3);
+        });
+        assert!(cpu.halted);
+        assert_eq!(cpu.registers.get(&Register::AX), 0x0042);
+        assert_eq!(cpu.last_interrupt, Some(0));
+    }
+
+    #[test]
+    fn pusha_and_popa_save_every_register_but_restore_sp() {
+        // pusha; mov ax, 0x55; mov bp, 0x55; popa
+        let program = [0x60, 0xb8, 0x55, 0x00, 0xbd, 0x55, 0x00, 0x61];
+        let cpu = run_on(CpuModel::I80186, &program, |cpu| {
+            for (value, register) in [Register::AX, Register::CX, Register::DX, Register::BX].into_iter().enumerate() {
+                cpu.registers.set(&register, value as u16 + 1);
+            }
+            cpu.registers.sp = 0x100;
+            cpu.registers.set(&Register::BP, 6);
+            cpu.registers.set(&Register::SI, 7);
+            cpu.registers.set(&Register::DI, 8);
+        });
+        assert_eq!(cpu.registers.get(&Register::AX), 1);
+        assert_eq!(cpu.registers.get(&Register::BP), 6);
+        assert_eq!(cpu.registers.sp, 0x100);
+        // DI at the bottom, and SP as it was before the first push
+        assert_eq!(cpu.read_memory(0xf0, true), 8);
+        assert_eq!(cpu.read_memory(0xf6, true), 0x100);
+        assert_eq!(cpu.read_memory(0xfe, true), 1);
+    }
+
+    #[test]
+    fn enter_builds_a_frame_and_leave_drops_it() {
+        // enter 4, 1; mov si, bp; mov di, sp; leave
+        let program = [0xc8, 0x04, 0x00, 0x01, 0x89, 0xee, 0x89, 0xe7, 0xc9];
+        let cpu = run_on(CpuModel::I80186, &program, |cpu| {
+            cpu.registers.sp = 0x100;
+            cpu.registers.set(&Register::BP, 0x1234);
+        });
+        assert_eq!(cpu.registers.get(&Register::SI), 0xfe);
+        assert_eq!(cpu.registers.get(&Register::DI), 0xf8);
+        // Level 1 copies the new frame pointer into the frame
+        assert_eq!(cpu.read_memory(0xfc, true), 0xfe);
+        assert_eq!(cpu.registers.get(&Register::BP), 0x1234);
+        assert_eq!(cpu.registers.sp, 0x100);
+    }
+
+    #[test]
+    fn immediate_push_imul_and_masked_shift_counts() {
+        let program = [
+            0x6a, 0xfe, // push -2
+            0x59, // pop cx
+            0x6b, 0xc3, 0xfe, // imul ax, bx, -2
+            0xc1, 0xe0, 0x21, // shl ax, 33, which the 80186 takes as 1
+        ];
+        let cpu = run_on(CpuModel::I80186, &program, |cpu| cpu.registers.set(&Register::BX, 3));
+        assert_eq!(cpu.registers.get(&Register::CX), 0xfffe);
+        assert_eq!(cpu.registers.get(&Register::AX), 0xfff4);
+        assert_eq!(cpu.flags.to_string(), "CS");
+    }
+
+    #[test]
+    fn bound_faults_with_interrupt_5_outside_the_range() {
+        // bound ax, [bx]; hlt; and the handler at 3: mov cx, 1; hlt
+        let program = [0x62, 0x07, 0xf4, 0xb9, 0x01, 0x00, 0xf4];
+        let bounds = |ax| move |cpu: &mut Cpu| {
+            cpu.registers.set(&Register::AX, ax);
+            cpu.registers.set(&Register::BX, 0x20);
+            cpu.write_memory(0x20, true, 0);
+            cpu.write_memory(0x22, true, 10);
+            set_vector(cpu, 5, 3);
+        };
+        let cpu = run_on(CpuModel::I80186, &program, bounds(10));
+        assert_eq!((cpu.registers.ip, cpu.last_interrupt), (3, None));
+        let cpu = run_on(CpuModel::I80186, &program, bounds(11));
+        assert_eq!((cpu.registers.ip, cpu.last_interrupt), (7, Some(5)));
+        assert_eq!(cpu.registers.get(&Register::CX), 1);
+        // The saved IP is the BOUND's own
+        assert_eq!(cpu.read_memory(cpu.stack_address(), true), 0);
+    }
+
+    #[test]
+    fn ins_and_outs_move_strings_through_dx() {
+        // rep outsb; mov cx, 2; rep insb
+        let program = [0xf3, 0x6e, 0xb9, 0x02, 0x00, 0xf3, 0x6c];
+        let latch = Rc::new(RefCell::new(vec![0; 2]));
+        let cpu = run_on(CpuModel::I80186, &program, |cpu| {
+            strings(cpu, b"ab", b"", 2);
+            cpu.registers.set(&Register::DX, 0x61);
+            cpu.map_io(0x60..=0x61, Box::new(Latch { base: 0x60, registers: latch.clone() }));
+        });
+        assert_eq!(*latch.borrow(), vec![0, b'b']);
+        assert_eq!(&cpu.memory[0x3000..0x3002], b"bb");
+        assert_eq!((cpu.registers.si, cpu.registers.di), (2, 2));
+    }
 }
@@ -1,298 +1,295 @@
-use std::usize;
-
-use crate::{ cpu::Cpu, instruction::{ AddressingMode, Instruction, Operand, Operation, Register } };
-
-// Original tables for reference
-const REGISTER_TABLE: [(u8, [&str; 2]); 8] = [
-    (0b000, ["al", "ax"]),
-    (0b001, ["cl", "cx"]),
-    (0b010, ["dl", "dx"]),
-    (0b011, ["bl", "bx"]),
-    (0b100, ["ah", "sp"]),
-    (0b101, ["ch", "bp"]),
-    (0b110, ["dh", "si"]),
-    (0b111, ["bh", "di"]),
-];
-
-fn register_from_index(idx: u8, w: u8) -> Register {
+use std::fmt;
+
+use crate::{
+    cpu::{ Cpu, CpuModel },
+    instruction::{ AddressingMode, Displacement, Instruction, Operand, Operation, Prefixes, Register, Repeat },
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeErrorReason {
+    UnexpectedEnd, // The buffer ran out in the middle of an instruction
+    UnknownOpcode(u8),
+    UnknownGroupOperation {
+        opcode: u8,
+        reg: u8,
+    }, // Opcode is known but the reg field of its ModRM byte is not
+    InvalidRegister {
+        index: u8,
+        w: u8,
+    },
+    MemoryOperandRequired(u8), // LEA, LDS and LES encoded with a register operand
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodeError {
+    pub offset: usize, // Offset of the first byte of the instruction
+    pub bytes: Vec<u8>, // Bytes consumed before decoding failed
+    pub reason: DecodeErrorReason,
+}
+
+impl fmt::Display for DecodeErrorReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeErrorReason::UnexpectedEnd => write!(f, "unexpected end of input"),
+            DecodeErrorReason::UnknownOpcode(opcode) => write!(f, "unknown opcode {:#04x}", opcode),
+            DecodeErrorReason::UnknownGroupOperation { opcode, reg } => {
+                write!(f, "unknown operation /{} for opcode {:#04x}", reg, opcode)
+            }
+            DecodeErrorReason::InvalidRegister { index, w } => {
+                write!(f, "invalid register index {:#05b} (w={})", index, w)
+            }
+            DecodeErrorReason::MemoryOperandRequired(opcode) => {
+                write!(f, "opcode {:#04x} requires a memory operand", opcode)
+            }
+        }
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at offset {:#x} (bytes:", self.reason, self.offset)?;
+        for byte in &self.bytes {
+            write!(f, " {:02x}", byte)?;
+        }
+        write!(f, ")")
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+// Reads instruction bytes while remembering where the instruction started
+struct Cursor<'a> {
+    buffer: &'a [u8],
+    start: usize,
+    offset: usize,
+    ip: u16, // IP of the first byte, which relative jump targets are computed from
+}
+
+impl Cursor<'_> {
+    fn next_u8(&mut self) -> Result<u8, DecodeError> {
+        let byte = self.peek_u8()?;
+        self.offset += 1;
+        Ok(byte)
+    }
+
+    fn next_u16(&mut self) -> Result<u16, DecodeError> {
+        let low_byte = self.next_u8()? as u16;
+        let high_byte = self.next_u8()? as u16;
+        Ok((high_byte << 8) | low_byte)
+    }
+
+    fn peek_u8(&self) -> Result<u8, DecodeError> {
+        match self.buffer.get(self.offset) {
+            Some(byte) => Ok(*byte),
+            None => Err(self.error(DecodeErrorReason::UnexpectedEnd)),
+        }
+    }
+
+    // IP of the byte after the ones read so far
+    fn next_ip(&self) -> u16 {
+        self.ip.wrapping_add((self.offset - self.start) as u16)
+    }
+
+    fn bytes(&self) -> Vec<u8> {
+        self.buffer[self.start..self.offset.min(self.buffer.len())].to_vec()
+    }
+
+    fn error(&self, reason: DecodeErrorReason) -> DecodeError {
+        DecodeError { offset: self.start, bytes: self.bytes(), reason }
+    }
+}
+
+fn register_from_index(idx: u8, w: u8) -> Result<Register, DecodeErrorReason> {
     match (idx, w) {
-        (0b000, 0) => Register::AL,
-        (0b000, 1) => Register::AX,
-        (0b001, 0) => Register::CL,
-        (0b001, 1) => Register::CX,
-        (0b010, 0) => Register::DL,
-        (0b010, 1) => Register::DX,
-        (0b011, 0) => Register::BL,
-        (0b011, 1) => Register::BX,
-        (0b100, 0) => Register::AH,
-        (0b100, 1) => Register::SP,
-        (0b101, 0) => Register::CH,
-        (0b101, 1) => Register::BP,
-        (0b110, 0) => Register::DH,
-        (0b110, 1) => Register::SI,
-        (0b111, 0) => Register::BH,
-        (0b111, 1) => Register::DI,
-        _ => panic!("Invalid register index: {}, {}", idx, w),
+        (0b000, 0) => Ok(Register::AL),
+        (0b000, 1) => Ok(Register::AX),
+        (0b001, 0) => Ok(Register::CL),
+        (0b001, 1) => Ok(Register::CX),
+        (0b010, 0) => Ok(Register::DL),
+        (0b010, 1) => Ok(Register::DX),
+        (0b011, 0) => Ok(Register::BL),
+        (0b011, 1) => Ok(Register::BX),
+        (0b100, 0) => Ok(Register::AH),
+        (0b100, 1) => Ok(Register::SP),
+        (0b101, 0) => Ok(Register::CH),
+        (0b101, 1) => Ok(Register::BP),
+        (0b110, 0) => Ok(Register::DH),
+        (0b110, 1) => Ok(Register::SI),
+        (0b111, 0) => Ok(Register::BH),
+        (0b111, 1) => Ok(Register::DI),
+        _ => Err(DecodeErrorReason::InvalidRegister { index: idx, w }),
     }
 }
 
-fn operation_from_opcode(opcode: u8) -> Operation {
+fn segment_register_from_index(idx: u8) -> Register {
+    match idx & 0b11 {
+        0b00 => Register::ES,
+        0b01 => Register::CS,
+        0b10 => Register::SS,
+        _ => Register::DS,
+    }
+}
+
+// Opcodes of the "Reg/Mem with Register to either" forms (first 6 bits)
+fn operation_from_opcode(opcode: u8) -> Result<Operation, DecodeErrorReason> {
     match opcode {
-        0b100010 => Operation::Mov, // Register/memory to/from register
-        0b1100011 => Operation::Mov, // Immediate to register/memory
-        0b1011 => Operation::Mov, // Immediate to register
-        0b1010000 => Operation::Mov, // Memory to accumulator
-        0b1010001 => Operation::Mov, // Accumulator to memory
-        0b000000 => Operation::Add, // Register/memory with register to either
-        0b100000 => Operation::Add, // Immediate to register/memory
-        0b0000010 => Operation::Add, // Immediate to accumulator
-        0b001010 => Operation::Sub, // Register/memory with register to either
-        0b100000 => Operation::Sub, // Immediate to register/memory (same opcode as ADD, but reg field differs)
-        0b0010110 => Operation::Sub, // Immediate to accumulator
-        0b001110 => Operation::Cmp, // Register/memory with register
-        0b100000 => Operation::Cmp, // Immediate with register/memory (same opcode as ADD, but reg field differs)
-        0b0011110 => Operation::Cmp, // Immediate with accumulator
-        0b01110100 => Operation::Jz, // Jump if zero/equal
-        0b01110101 => Operation::Jnz, // Jump not zero/not equal
-        0b01111100 => Operation::Jl, // Jump if less
-        0b01111110 => Operation::Jle, // Jump if less or equal
-        0b01110010 => Operation::Jb, // Jump if below
-        0b01110110 => Operation::Jbe, // Jump if below or equal
-        0b01111010 => Operation::Jp, // Jump if parity
-        0b01110000 => Operation::Jo, // Jump if overflow
-        0b01111000 => Operation::Js, // Jump if sign
-        0b01110101 => Operation::Jne, // Jump if not equal
-        0b01111101 => Operation::Jnl, // Jump if not less
-        0b01111111 => Operation::Jg, // Jump if greater
-        0b01110011 => Operation::Jnb, // Jump if not below
-        0b01110111 => Operation::Ja, // Jump if above
-        0b01111011 => Operation::Jnp, // Jump if not parity
-        0b01110001 => Operation::Jno, // Jump if not overflow
-        0b01111001 => Operation::Jns, // Jump if not sign
-        0b11100010 => Operation::Loop, // Loop
-        0b11100001 => Operation::Loopz, // Loop if zero
-        0b11100000 => Operation::Loopnz, // Loop if not zero
-        0b11100011 => Operation::Jcxz, // Jump if CX is zero
-        _ => panic!("Unsupported operation code: {:b}", opcode),
+        0b100010 => Ok(Operation::Mov), // Register/memory to/from register
+        0b000000 => Ok(Operation::Add), // Register/memory with register to either
+        0b000100 => Ok(Operation::Adc), // Register/memory with register to either
+        0b000110 => Ok(Operation::Sbb), // Register/memory with register to either
+        0b001010 => Ok(Operation::Sub), // Register/memory with register to either
+        0b001110 => Ok(Operation::Cmp), // Register/memory with register
+        0b001000 => Ok(Operation::And), // Register/memory with register to either
+        0b000010 => Ok(Operation::Or), // Register/memory with register to either
+        0b001100 => Ok(Operation::Xor), // Register/memory with register to either
+        0b100001 => Ok(Operation::Test), // Register/memory and register
+        _ => Err(DecodeErrorReason::UnknownOpcode(opcode)),
+    }
+}
+
+// Shift or rotate selected by the reg field of a group 2 opcode (/6 is not used)
+fn shift_from_reg(opcode: u8, reg: u8) -> Result<Operation, DecodeErrorReason> {
+    match reg {
+        0b000 => Ok(Operation::Rol),
+        0b001 => Ok(Operation::Ror),
+        0b010 => Ok(Operation::Rcl),
+        0b011 => Ok(Operation::Rcr),
+        0b100 => Ok(Operation::Shl),
+        0b101 => Ok(Operation::Shr),
+        0b111 => Ok(Operation::Sar),
+        _ => Err(DecodeErrorReason::UnknownGroupOperation { opcode, reg }),
     }
 }
 
-fn decode_rm_operand(mod_val: u8, rm: u8, w: u8, bytes: &[u8], offset: usize) -> (Operand, usize) {
-    match mod_val {
+// Base and index registers selected by the r/m field of a memory operand
+fn effective_address_registers(rm: u8) -> (Option<Register>, Option<Register>) {
+    match rm {
+        0b000 => (Some(Register::BX), Some(Register::SI)),
+        0b001 => (Some(Register::BX), Some(Register::DI)),
+        0b010 => (Some(Register::BP), Some(Register::SI)),
+        0b011 => (Some(Register::BP), Some(Register::DI)),
+        0b100 => (None, Some(Register::SI)),
+        0b101 => (None, Some(Register::DI)),
+        0b110 => (Some(Register::BP), None),
+        _ => (Some(Register::BX), None),
+    }
+}
+
+fn decode_rm_operand(
+    cursor: &mut Cursor,
+    mod_val: u8,
+    rm: u8,
+    w: u8
+) -> Result<Operand, DecodeError> {
+    let displacement = match mod_val {
         0b11 => {
             // Register mode
-            (Operand::Register(register_from_index(rm, w)), 0)
-        }
-        0b00 => {
-            // Memory mode, no displacement (except direct address)
-            if rm == 0b110 {
-                // Direct address
-                let addr = ((bytes[offset + 1] as u16) << 8) | (bytes[offset] as u16);
-                (Operand::Memory(AddressingMode::Direct(addr.try_into().unwrap())), 2)
-            } else {
-                let memory = match rm {
-                    0b000 =>
-                        AddressingMode::Memory {
-                            base: Some(Register::BX),
-                            index: Some(Register::SI),
-                            displacement: None,
-                        },
-                    0b001 =>
-                        AddressingMode::Memory {
-                            base: Some(Register::BX),
-                            index: Some(Register::DI),
-                            displacement: None,
-                        },
-                    0b010 =>
-                        AddressingMode::Memory {
-                            base: Some(Register::BP),
-                            index: Some(Register::SI),
-                            displacement: None,
-                        },
-                    0b011 =>
-                        AddressingMode::Memory {
-                            base: Some(Register::BP),
-                            index: Some(Register::DI),
-                            displacement: None,
-                        },
-                    0b100 =>
-                        AddressingMode::Memory {
-                            base: None,
-                            index: Some(Register::SI),
-                            displacement: None,
-                        },
-                    0b101 =>
-                        AddressingMode::Memory {
-                            base: None,
-                            index: Some(Register::DI),
-                            displacement: None,
-                        },
-                    0b111 =>
-                        AddressingMode::Memory {
-                            base: Some(Register::BX),
-                            index: None,
-                            displacement: None,
-                        },
-                    _ => unreachable!(),
-                };
-                (Operand::Memory(memory), 0)
-            }
-        }
-        0b01 => {
-            // Memory mode, 8-bit displacement
-            let displacement = bytes[offset] as i8 as i16; // Sign extend to 16 bits
-            let memory = match rm {
-                0b000 =>
-                    AddressingMode::Memory {
-                        base: Some(Register::BX),
-                        index: Some(Register::SI),
-                        displacement: Some(displacement),
-                    },
-                0b001 =>
-                    AddressingMode::Memory {
-                        base: Some(Register::BX),
-                        index: Some(Register::DI),
-                        displacement: Some(displacement),
-                    },
-                0b010 =>
-                    AddressingMode::Memory {
-                        base: Some(Register::BP),
-                        index: Some(Register::SI),
-                        displacement: Some(displacement),
-                    },
-                0b011 =>
-                    AddressingMode::Memory {
-                        base: Some(Register::BP),
-                        index: Some(Register::DI),
-                        displacement: Some(displacement),
-                    },
-                0b100 =>
-                    AddressingMode::Memory {
-                        base: None,
-                        index: Some(Register::SI),
-                        displacement: Some(displacement),
-                    },
-                0b101 =>
-                    AddressingMode::Memory {
-                        base: None,
-                        index: Some(Register::DI),
-                        displacement: Some(displacement),
-                    },
-                0b110 =>
-                    AddressingMode::Memory {
-                        base: Some(Register::BP),
-                        index: None,
-                        displacement: Some(displacement),
-                    },
-                0b111 =>
-                    AddressingMode::Memory {
-                        base: Some(Register::BX),
-                        index: None,
-                        displacement: Some(displacement),
-                    },
-                _ => unreachable!(),
-            };
-            (Operand::Memory(memory), 1)
-        }
-        0b10 => {
-            // Memory mode, 16-bit displacement
-            let displacement = (((bytes[offset + 1] as u16) << 8) | (bytes[offset] as u16)) as i16;
-            let memory = match rm {
-                0b000 =>
-                    AddressingMode::Memory {
-                        base: Some(Register::BX),
-                        index: Some(Register::SI),
-                        displacement: Some(displacement),
-                    },
-                0b001 =>
-                    AddressingMode::Memory {
-                        base: Some(Register::BX),
-                        index: Some(Register::DI),
-                        displacement: Some(displacement),
-                    },
-                0b010 =>
-                    AddressingMode::Memory {
-                        base: Some(Register::BP),
-                        index: Some(Register::SI),
-                        displacement: Some(displacement),
-                    },
-                0b011 =>
-                    AddressingMode::Memory {
-                        base: Some(Register::BP),
-                        index: Some(Register::DI),
-                        displacement: Some(displacement),
-                    },
-                0b100 =>
-                    AddressingMode::Memory {
-                        base: None,
-                        index: Some(Register::SI),
-                        displacement: Some(displacement),
-                    },
-                0b101 =>
-                    AddressingMode::Memory {
-                        base: None,
-                        index: Some(Register::DI),
-                        displacement: Some(displacement),
-                    },
-                0b110 =>
-                    AddressingMode::Memory {
-                        base: Some(Register::BP),
-                        index: None,
-                        displacement: Some(displacement),
-                    },
-                0b111 =>
-                    AddressingMode::Memory {
-                        base: Some(Register::BX),
-                        index: None,
-                        displacement: Some(displacement),
-                    },
-                _ => unreachable!(),
-            };
-            (Operand::Memory(memory), 2)
+            let register = register_from_index(rm, w).map_err(|reason| cursor.error(reason))?;
+            return Ok(Operand::Register(register));
         }
-        _ => unreachable!(),
-    }
+        0b00 if rm == 0b110 => {
+            // Direct address
+            let addr = cursor.next_u16()?;
+            return Ok(Operand::Memory(AddressingMode::Direct(addr as i16)));
+        }
+        0b00 => None, // Memory mode, no displacement
+        0b01 => Some(Displacement { value: cursor.next_u8()? as i8 as i16, wide: false }), // Memory mode, 8-bit displacement sign extended
+        _ => Some(Displacement { value: cursor.next_u16()? as i16, wide: true }), // Memory mode, 16-bit displacement
+    };
+
+    let (base, index) = effective_address_registers(rm);
+    Ok(Operand::Memory(AddressingMode::Memory { base, index, displacement }))
+}
+
+// Number of bytes fetched from CS:IP for a single instruction
+const FETCH_WINDOW: u16 = 16;
+
+// Decodes the instruction at CS:IP and advances IP past it
+pub fn decode(cpu: &mut Cpu) -> Result<Instruction, DecodeError> {
+    let (cs, ip) = (cpu.registers.cs, cpu.registers.ip);
+    // Fetch byte by byte so IP wraps within the code segment and the address at 1 MiB
+    let window: Vec<u8> = (0..FETCH_WINDOW)
+        .map(|i| cpu.memory[cpu.physical_address(cs, ip.wrapping_add(i))])
+        .collect();
+    let (instruction, length) = decode_instruction(&window, 0, ip, cpu.model).map_err(|err| DecodeError {
+        offset: cpu.physical_address(cs, ip),
+        ..err
+    })?;
+    cpu.registers.ip = ip.wrapping_add(length as u16);
+    Ok(instruction)
+}
+
+// Decodes the instruction starting at `offset` in `buffer`, returning it with the offset of the next instruction
+pub fn decode_at(
+    buffer: &[u8],
+    offset: usize,
+    model: CpuModel
+) -> Result<(Instruction, usize), DecodeError> {
+    decode_instruction(buffer, offset, offset as u16, model)
 }
 
-pub fn decode(cpu: &mut Cpu) -> Instruction {
-    let mut offset = cpu.registers.ip as usize;
-    let start_offset = offset;
-    let buffer = &cpu.memory;
-    let current_byte = buffer[offset];
+fn decode_instruction(
+    buffer: &[u8],
+    offset: usize,
+    ip: u16,
+    model: CpuModel
+) -> Result<(Instruction, usize), DecodeError> {
+    let mut cursor = Cursor { buffer, start: offset, offset, ip };
+
+    let mut prefixes = Prefixes::default();
+    loop {
+        match cursor.peek_u8()? {
+            v if (v & 0b11100111) == 0b00100110 => {
+                prefixes.segment = Some(segment_register_from_index(v >> 3));
+            }
+            0b11110000 => {
+                prefixes.lock = true;
+            }
+            0b11110010 => {
+                prefixes.repeat = Some(Repeat::Repne);
+            }
+            0b11110011 => {
+                prefixes.repeat = Some(Repeat::Rep);
+            }
+            _ => {
+                break;
+            }
+        }
+        cursor.next_u8()?;
+    }
+    let current_byte = cursor.next_u8()?;
 
     // Match different instruction patterns
     let instruction = match current_byte {
-        // Reg/Mem with Register to either (MOV, ADD, SUB, CMP, etc.)
+        // Reg/Mem with Register to either (MOV, ADD, ADC, SUB, SBB, CMP, AND, OR, XOR, TEST)
         v if
             (v >> 2) == 0b100010 || // MOV r/m, r/r, r/m
             (v >> 2) == 0b000000 || // ADD r/m, r/r, r/m
+            (v >> 2) == 0b000100 || // ADC r/m, r/r, r/m
+            (v >> 2) == 0b000110 || // SBB r/m, r/r, r/m
             (v >> 2) == 0b001010 || // SUB r/m, r/r, r/m
-            (v >> 2) == 0b001110 // CMP r/m, r/r, r/m
+            (v >> 2) == 0b001110 || // CMP r/m, r/r, r/m
+            (v >> 2) == 0b001000 || // AND r/m, r/r, r/m
+            (v >> 2) == 0b000010 || // OR r/m, r/r, r/m
+            (v >> 2) == 0b001100 || // XOR r/m, r/r, r/m
+            (v >> 1) == 0b1000010 // TEST r/m, r (no direction bit)
         => {
             let opcode = v >> 2;
             let d = (v >> 1) & 0b1; // Direction bit
             let w = v & 0b1; // Word/byte bit
 
-            offset += 1;
-
-            let mod_val = (buffer[offset] >> 6) & 0b11;
-            let reg = (buffer[offset] >> 3) & 0b111;
-            let rm = buffer[offset] & 0b111;
-
-            offset += 1;
+            let modrm = cursor.next_u8()?;
+            let mod_val = (modrm >> 6) & 0b11;
+            let reg = (modrm >> 3) & 0b111;
+            let rm = modrm & 0b111;
 
             // Get register operand
-            let reg_operand = Operand::Register(register_from_index(reg, w));
-
-            // Get r/m operand and update offset
-            let (rm_operand, additional_bytes) = decode_rm_operand(
-                mod_val,
-                rm,
-                w,
-                &buffer[offset..],
-                0
+            let reg_operand = Operand::Register(
+                register_from_index(reg, w).map_err(|reason| cursor.error(reason))?
             );
-            offset += additional_bytes;
+
+            // Get r/m operand
+            let rm_operand = decode_rm_operand(&mut cursor, mod_val, rm, w)?;
 
             // Determine source and destination based on direction bit
             let (source, destination) = if d == 0 {
@@ -301,14 +298,16 @@ pub fn decode(cpu: &mut Cpu) -> Instruction {
                 (rm_operand, reg_operand)
             };
 
-            let operation = operation_from_opcode(opcode);
-            let instruction_bytes = buffer[start_offset..offset].to_vec();
+            let operation = operation_from_opcode(opcode).map_err(|reason| cursor.error(reason))?;
 
             Instruction {
                 operation,
-                destination,
+                destination: Some(destination),
                 source: Some(source),
-                bytes: instruction_bytes,
+                third: None,
+                wide: w == 1,
+                prefixes,
+                bytes: cursor.bytes(),
             }
         }
 
@@ -317,96 +316,85 @@ pub fn decode(cpu: &mut Cpu) -> Instruction {
             let w = (v >> 3) & 0b1; // Word/byte bit
             let reg = v & 0b111; // Register field
 
-            offset += 1;
-
-            let immediate = if w == 1 {
-                let value = ((buffer[offset + 1] as u16) << 8) | (buffer[offset] as u16);
-                offset += 2;
-                value
-            } else {
-                let value = buffer[offset] as u16;
-                offset += 1;
-                value
-            };
+            let immediate = if w == 1 { cursor.next_u16()? } else { cursor.next_u8()? as u16 };
 
             let operation = Operation::Mov;
-            let destination = Operand::Register(register_from_index(reg, w));
-            let source = Operand::Immediate(immediate as i16);
-            let instruction_bytes = buffer[start_offset..offset].to_vec();
+            let destination = Operand::Register(
+                register_from_index(reg, w).map_err(|reason| cursor.error(reason))?
+            );
+            let source = Operand::Immediate { value: immediate as i16, wide: w == 1 };
 
             Instruction {
                 operation,
-                destination,
+                destination: Some(destination),
                 source: Some(source),
-                bytes: instruction_bytes,
+                third: None,
+                wide: w == 1,
+                prefixes,
+                bytes: cursor.bytes(),
             }
         }
 
-        // Immediate to Register/Memory (MOV, ADD, SUB, CMP)
+        // Immediate to Register/Memory (MOV, ADD, OR, ADC, SBB, AND, SUB, XOR, CMP)
         v if (v >> 1) == 0b1100011 || (v & 0b11111100) == 0b10000000 => {
+            let modrm = cursor.next_u8()?;
+            let reg_field = (modrm >> 3) & 0b111;
+
             let op_type = if (v >> 1) == 0b1100011 {
                 // C6/C7 - MOV immediate to r/m
                 Operation::Mov
             } else {
                 // 80/81/83 - need to check reg field for specific operation
-                let next_offset = offset + 1;
-
-                let reg_field = (buffer[next_offset] >> 3) & 0b111;
                 match reg_field {
                     0b000 => Operation::Add,
+                    0b001 => Operation::Or,
+                    0b010 => Operation::Adc,
+                    0b011 => Operation::Sbb,
+                    0b100 => Operation::And,
                     0b101 => Operation::Sub,
+                    0b110 => Operation::Xor,
                     0b111 => Operation::Cmp,
-                    _ => panic!("Unsupported reg field for immediate group: {}", reg_field),
+                    _ => {
+                        return Err(
+                            cursor.error(DecodeErrorReason::UnknownGroupOperation {
+                                opcode: v,
+                                reg: reg_field,
+                            })
+                        );
+                    }
                 }
             };
 
             let w = v & 0b1; // Word/byte bit
             let s = if (v >> 1) == 0b1100011 { 0 } else { (v >> 1) & 0b1 }; // Sign extend for 80/81/83
 
-            offset += 1;
-
-            let mod_val = (buffer[offset] >> 6) & 0b11;
-            let rm = buffer[offset] & 0b111;
-
-            offset += 1;
+            let mod_val = (modrm >> 6) & 0b11;
+            let rm = modrm & 0b111;
 
-            // Get r/m operand and update offset
-            let (destination, additional_bytes) = decode_rm_operand(
-                mod_val,
-                rm,
-                w,
-                &buffer[offset..],
-                0
-            );
-            offset += additional_bytes;
+            // Get r/m operand
+            let destination = decode_rm_operand(&mut cursor, mod_val, rm, w)?;
 
             let immediate = if w == 1 && s == 0 {
                 // 16-bit immediate
-
-                let value = ((buffer[offset + 1] as u16) << 8) | (buffer[offset] as u16);
-                offset += 2;
-                value
+                cursor.next_u16()?
+            } else if s == 1 && w == 1 {
+                // Sign-extend 8-bit to 16-bit
+                cursor.next_u8()? as i8 as i16 as u16
             } else {
-                // 8-bit immediate (sign-extended if needed)
-
-                let value = if s == 1 && w == 1 {
-                    // Sign-extend 8-bit to 16-bit
-                    buffer[offset] as i8 as i16 as u16
-                } else {
-                    buffer[offset] as u16
-                };
-                offset += 1;
-                value
+                // 8-bit immediate
+                cursor.next_u8()? as u16
             };
 
-            let source = Operand::Immediate(immediate as i16);
-            let instruction_bytes = buffer[start_offset..offset].to_vec();
+            let source = Operand::Immediate { value: immediate as i16, wide: w == 1 && s == 0 };
 
             Instruction {
                 operation: op_type,
-                destination,
+                destination: Some(destination),
                 source: Some(source),
-                bytes: instruction_bytes,
+                third: None,
+                wide: w == 1,
+                prefixes,
+                bytes: cursor.bytes(),
             }
         }
 
@@ -414,28 +402,21 @@ pub fn decode(cpu: &mut Cpu) -> Instruction {
         v if (v >> 1) == 0b1010000 => {
             let w = v & 0b1;
 
-            offset += 1;
-
-            let addr = if w == 1 {
-                let value = ((buffer[offset + 1] as u16) << 8) | (buffer[offset] as u16);
-                offset += 2;
-                value
-            } else {
-                let value = buffer[offset] as u16;
-                offset += 1;
-                value
-            };
+            // The address is always 16 bits, w only selects AL or AX
+            let addr = cursor.next_u16()?;
 
             let operation = Operation::Mov;
             let destination = Operand::Register(if w == 1 { Register::AX } else { Register::AL });
             let source = Operand::Memory(AddressingMode::Direct(addr as i16));
-            let instruction_bytes = buffer[start_offset..offset].to_vec();
 
             Instruction {
                 operation,
-                destination,
+                destination: Some(destination),
                 source: Some(source),
-                bytes: instruction_bytes,
+                third: None,
+                wide: w == 1,
+                prefixes,
+                bytes: cursor.bytes(),
             }
         }
 
@@ -443,74 +424,613 @@ pub fn decode(cpu: &mut Cpu) -> Instruction {
         v if (v >> 1) == 0b1010001 => {
             let w = v & 0b1;
 
-            offset += 1;
-
-            let addr = if w == 1 {
-                let value = ((buffer[offset + 1] as u16) << 8) | (buffer[offset] as u16);
-                offset += 2;
-                value
-            } else {
-                let value = buffer[offset] as u16;
-                offset += 1;
-                value
-            };
+            // The address is always 16 bits, w only selects AL or AX
+            let addr = cursor.next_u16()?;
 
             let operation = Operation::Mov;
             let source = Operand::Register(if w == 1 { Register::AX } else { Register::AL });
             let destination = Operand::Memory(AddressingMode::Direct(addr as i16));
-            let instruction_bytes = buffer[start_offset..offset].to_vec();
 
             Instruction {
                 operation,
-                destination,
+                destination: Some(destination),
                 source: Some(source),
-                bytes: instruction_bytes,
+                third: None,
+                wide: w == 1,
+                prefixes,
+                bytes: cursor.bytes(),
             }
         }
 
-        // Immediate to Accumulator (ADD, SUB, CMP)
+        // Immediate to Accumulator (ADD, ADC, SUB, SBB, CMP, AND, OR, XOR, TEST)
         v if
             v == 0b00000100 ||
             v == 0b00000101 || // ADD AL, imm8 / ADD AX, imm16
+            v == 0b00010100 ||
+            v == 0b00010101 || // ADC AL, imm8 / ADC AX, imm16
+            v == 0b00011100 ||
+            v == 0b00011101 || // SBB AL, imm8 / SBB AX, imm16
             v == 0b00101100 ||
             v == 0b00101101 || // SUB AL, imm8 / SUB AX, imm16
             v == 0b00111100 ||
-            v == 0b00111101 // CMP AL, imm8 / CMP AX, imm16
+            v == 0b00111101 || // CMP AL, imm8 / CMP AX, imm16
+            v == 0b00100100 ||
+            v == 0b00100101 || // AND AL, imm8 / AND AX, imm16
+            v == 0b00001100 ||
+            v == 0b00001101 || // OR AL, imm8 / OR AX, imm16
+            v == 0b00110100 ||
+            v == 0b00110101 || // XOR AL, imm8 / XOR AX, imm16
+            v == 0b10101000 ||
+            v == 0b10101001 // TEST AL, imm8 / TEST AX, imm16
         => {
             let w = v & 0b1; // Word/byte bit
             let operation = match v & 0b11111110 {
                 0b00000100 => Operation::Add,
+                0b00010100 => Operation::Adc,
                 0b00101100 => Operation::Sub,
+                0b00011100 => Operation::Sbb,
                 0b00111100 => Operation::Cmp,
+                0b00100100 => Operation::And,
+                0b00001100 => Operation::Or,
+                0b00110100 => Operation::Xor,
+                0b10101000 => Operation::Test,
                 _ => unreachable!(),
             };
 
-            offset += 1;
-
             let immediate = if w == 1 {
-                let value = ((buffer[offset + 1] as u16) << 8) | (buffer[offset] as u16);
-                offset += 2;
-                value as i16
+                cursor.next_u16()? as i16
             } else {
-                let value = buffer[offset] as i8 as i16; // Sign extend
-                offset += 1;
-                value
+                cursor.next_u8()? as i8 as i16 // Sign extend
             };
 
             let destination = Operand::Register(if w == 1 { Register::AX } else { Register::AL });
-            let source = Operand::Immediate(immediate);
-            let instruction_bytes = buffer[start_offset..offset].to_vec();
+            let source = Operand::Immediate { value: immediate, wide: w == 1 };
 
             Instruction {
                 operation,
-                destination,
+                destination: Some(destination),
+                source: Some(source),
+                third: None,
+                wide: w == 1,
+                prefixes,
+                bytes: cursor.bytes(),
+            }
+        }
+
+        // Group 3 on register/memory (TEST immediate, NOT, NEG, MUL, IMUL, DIV, IDIV)
+        v if (v >> 1) == 0b1111011 => {
+            let w = v & 0b1; // Word/byte bit
+
+            let modrm = cursor.next_u8()?;
+            let mod_val = (modrm >> 6) & 0b11;
+            let reg_field = (modrm >> 3) & 0b111;
+            let rm = modrm & 0b111;
+
+            let operation = match reg_field {
+                0b000 => Operation::Test,
+                0b010 => Operation::Not,
+                0b011 => Operation::Neg,
+                0b100 => Operation::Mul,
+                0b101 => Operation::Imul,
+                0b110 => Operation::Div,
+                0b111 => Operation::Idiv,
+                _ => {
+                    return Err(
+                        cursor.error(DecodeErrorReason::UnknownGroupOperation {
+                            opcode: v,
+                            reg: reg_field,
+                        })
+                    );
+                }
+            };
+
+            let destination = decode_rm_operand(&mut cursor, mod_val, rm, w)?;
+
+            // Only TEST carries an immediate
+            let source = match operation {
+                Operation::Test => {
+                    let immediate = if w == 1 { cursor.next_u16()? } else { cursor.next_u8()? as u16 };
+                    Some(Operand::Immediate { value: immediate as i16, wide: w == 1 })
+                }
+                _ => None,
+            };
+
+            Instruction {
+                operation,
+                destination: Some(destination),
+                source,
+                third: None,
+                wide: w == 1,
+                prefixes,
+                bytes: cursor.bytes(),
+            }
+        }
+
+        // Group 2 shifts and rotates by 1 or by CL
+        v if (v >> 2) == 0b110100 => {
+            let v_bit = (v >> 1) & 0b1; // Count in CL (1) or a count of 1 (0)
+            let w = v & 0b1; // Word/byte bit
+
+            let modrm = cursor.next_u8()?;
+            let mod_val = (modrm >> 6) & 0b11;
+            let reg_field = (modrm >> 3) & 0b111;
+            let rm = modrm & 0b111;
+
+            let operation = shift_from_reg(v, reg_field).map_err(|reason| cursor.error(reason))?;
+
+            let destination = decode_rm_operand(&mut cursor, mod_val, rm, w)?;
+            let source = if v_bit == 1 {
+                Operand::Register(Register::CL)
+            } else {
+                Operand::Immediate { value: 1, wide: false }
+            };
+
+            Instruction {
+                operation,
+                destination: Some(destination),
+                source: Some(source),
+                third: None,
+                wide: w == 1,
+                prefixes,
+                bytes: cursor.bytes(),
+            }
+        }
+
+        // Increment/decrement 16-bit register (INC, DEC)
+        v if (v >> 4) == 0b0100 => {
+            let operation = if (v >> 3) & 0b1 == 0 { Operation::Inc } else { Operation::Dec };
+            let destination = Operand::Register(
+                register_from_index(v & 0b111, 1).map_err(|reason| cursor.error(reason))?
+            );
+
+            Instruction {
+                operation,
+                destination: Some(destination),
+                source: None,
+                third: None,
+                wide: true,
+                prefixes,
+                bytes: cursor.bytes(),
+            }
+        }
+
+        // Group 4/5 on register/memory (INC, DEC, CALL, JMP, PUSH)
+        v if (v >> 1) == 0b1111111 => {
+            let w = v & 0b1; // Word/byte bit
+
+            let modrm = cursor.next_u8()?;
+            let mod_val = (modrm >> 6) & 0b11;
+            let reg_field = (modrm >> 3) & 0b111;
+            let rm = modrm & 0b111;
+
+            let operation = match (reg_field, w) {
+                (0b000, _) => Operation::Inc,
+                (0b001, _) => Operation::Dec,
+                (0b010, 1) => Operation::Call,
+                (0b011, 1) if mod_val != 0b11 => Operation::CallFar,
+                (0b100, 1) => Operation::Jmp,
+                (0b101, 1) if mod_val != 0b11 => Operation::JmpFar,
+                (0b110, 1) => Operation::Push,
+                _ => {
+                    return Err(
+                        cursor.error(DecodeErrorReason::UnknownGroupOperation {
+                            opcode: v,
+                            reg: reg_field,
+                        })
+                    );
+                }
+            };
+
+            let destination = decode_rm_operand(&mut cursor, mod_val, rm, w)?;
+
+            Instruction {
+                operation,
+                destination: Some(destination),
+                source: None,
+                third: None,
+                wide: w == 1,
+                prefixes,
+                bytes: cursor.bytes(),
+            }
+        }
+
+        // Push/pop 16-bit register
+        v if (v >> 4) == 0b0101 => {
+            let operation = if (v >> 3) & 0b1 == 0 { Operation::Push } else { Operation::Pop };
+            let destination = Operand::Register(
+                register_from_index(v & 0b111, 1).map_err(|reason| cursor.error(reason))?
+            );
+
+            Instruction {
+                operation,
+                destination: Some(destination),
+                source: None,
+                third: None,
+                wide: true,
+                prefixes,
+                bytes: cursor.bytes(),
+            }
+        }
+
+        // Segment register to/from register/memory (MOV)
+        0b10001100 | 0b10001110 => {
+            let modrm = cursor.next_u8()?;
+            let mod_val = (modrm >> 6) & 0b11;
+            let sreg = Operand::Register(segment_register_from_index((modrm >> 3) & 0b11));
+            let rm_operand = decode_rm_operand(&mut cursor, mod_val, modrm & 0b111, 1)?;
+
+            let (destination, source) = if current_byte == 0b10001110 {
+                (sreg, rm_operand)
+            } else {
+                (rm_operand, sreg)
+            };
+
+            Instruction {
+                operation: Operation::Mov,
+                destination: Some(destination),
                 source: Some(source),
-                bytes: instruction_bytes,
+                third: None,
+                wide: true,
+                prefixes,
+                bytes: cursor.bytes(),
+            }
+        }
+
+        // Push/pop segment register (POP CS, 0x0F, is not supported)
+        v if (v & 0b11100110) == 0b00000110 && v != 0b00001111 => {
+            let operation = if v & 0b1 == 0 { Operation::Push } else { Operation::Pop };
+            let destination = Operand::Register(segment_register_from_index(v >> 3));
+
+            Instruction {
+                operation,
+                destination: Some(destination),
+                source: None,
+                third: None,
+                wide: true,
+                prefixes,
+                bytes: cursor.bytes(),
+            }
+        }
+
+        // Load effective address or far pointer (LEA, LDS, LES)
+        0b10001101 | 0b11000101 | 0b11000100 => {
+            let modrm = cursor.next_u8()?;
+            let mod_val = (modrm >> 6) & 0b11;
+            if mod_val == 0b11 {
+                return Err(cursor.error(DecodeErrorReason::MemoryOperandRequired(current_byte)));
+            }
+            let destination = Operand::Register(
+                register_from_index((modrm >> 3) & 0b111, 1).map_err(|reason| cursor.error(reason))?
+            );
+            let source = decode_rm_operand(&mut cursor, mod_val, modrm & 0b111, 1)?;
+            let operation = match current_byte {
+                0b10001101 => Operation::Lea,
+                0b11000101 => Operation::Lds,
+                _ => Operation::Les,
+            };
+
+            Instruction {
+                operation,
+                destination: Some(destination),
+                source: Some(source),
+                third: None,
+                wide: true,
+                prefixes,
+                bytes: cursor.bytes(),
+            }
+        }
+
+        // Exchange register/memory with register
+        0b10000110 | 0b10000111 => {
+            let w = current_byte & 0b1;
+            let modrm = cursor.next_u8()?;
+            let mod_val = (modrm >> 6) & 0b11;
+            let source = Operand::Register(
+                register_from_index((modrm >> 3) & 0b111, w).map_err(|reason| cursor.error(reason))?
+            );
+            let destination = decode_rm_operand(&mut cursor, mod_val, modrm & 0b111, w)?;
+
+            Instruction {
+                operation: Operation::Xchg,
+                destination: Some(destination),
+                source: Some(source),
+                third: None,
+                wide: w == 1,
+                prefixes,
+                bytes: cursor.bytes(),
+            }
+        }
+
+        // NOP, which is XCHG AX, AX
+        0b10010000 => {
+            Instruction {
+                operation: Operation::Nop,
+                destination: None,
+                source: None,
+                third: None,
+                wide: true,
+                prefixes,
+                bytes: cursor.bytes(),
+            }
+        }
+
+        // Exchange register with accumulator
+        v if (v >> 3) == 0b10010 => {
+            let register = register_from_index(v & 0b111, 1).map_err(|reason| cursor.error(reason))?;
+
+            Instruction {
+                operation: Operation::Xchg,
+                destination: Some(Operand::Register(Register::AX)),
+                source: Some(Operand::Register(register)),
+                third: None,
+                wide: true,
+                prefixes,
+                bytes: cursor.bytes(),
+            }
+        }
+
+        // Single-byte flag and processor control (CLC, STC, CMC, CLD, STD, CLI, STI, LAHF, SAHF, HLT, WAIT)
+        0b11111000 | 0b11111001 | 0b11110101 | 0b11111100 | 0b11111101 | 0b11111010 | 0b11111011
+        | 0b10011111 | 0b10011110 | 0b11110100 | 0b10011011 => {
+            let operation = match current_byte {
+                0b11111000 => Operation::Clc,
+                0b11111001 => Operation::Stc,
+                0b11110101 => Operation::Cmc,
+                0b11111100 => Operation::Cld,
+                0b11111101 => Operation::Std,
+                0b11111010 => Operation::Cli,
+                0b11111011 => Operation::Sti,
+                0b10011111 => Operation::Lahf,
+                0b10011110 => Operation::Sahf,
+                0b11110100 => Operation::Hlt,
+                _ => Operation::Wait,
+            };
+
+            Instruction {
+                operation,
+                destination: None,
+                source: None,
+                third: None,
+                wide: false,
+                prefixes,
+                bytes: cursor.bytes(),
+            }
+        }
+
+        // Decimal and ASCII adjust without operands (DAA, DAS, AAA, AAS)
+        0b00100111 | 0b00101111 | 0b00110111 | 0b00111111 => {
+            let operation = match current_byte {
+                0b00100111 => Operation::Daa,
+                0b00101111 => Operation::Das,
+                0b00110111 => Operation::Aaa,
+                _ => Operation::Aas,
+            };
+
+            Instruction {
+                operation,
+                destination: None,
+                source: None,
+                third: None,
+                wide: false,
+                prefixes,
+                bytes: cursor.bytes(),
+            }
+        }
+
+        // AAM and AAD, whose second byte is the number base (10 in documented code)
+        0b11010100 | 0b11010101 => {
+            let operation = if current_byte == 0b11010100 { Operation::Aam } else { Operation::Aad };
+            let base = cursor.next_u8()?;
+
+            Instruction {
+                operation,
+                destination: Some(Operand::Immediate { value: base as i16, wide: false }),
+                source: None,
+                third: None,
+                wide: true,
+                prefixes,
+                bytes: cursor.bytes(),
+            }
+        }
+
+        // XLAT, CBW and CWD
+        0b11010111 | 0b10011000 | 0b10011001 => {
+            let operation = match current_byte {
+                0b11010111 => Operation::Xlat,
+                0b10011000 => Operation::Cbw,
+                _ => Operation::Cwd,
+            };
+
+            Instruction {
+                operation,
+                destination: None,
+                source: None,
+                third: None,
+                wide: current_byte != 0b11010111,
+                prefixes,
+                bytes: cursor.bytes(),
+            }
+        }
+
+        // Pop register/memory
+        0b10001111 => {
+            let modrm = cursor.next_u8()?;
+            let mod_val = (modrm >> 6) & 0b11;
+            let reg_field = (modrm >> 3) & 0b111;
+            let rm = modrm & 0b111;
+
+            if reg_field != 0b000 {
+                return Err(
+                    cursor.error(DecodeErrorReason::UnknownGroupOperation {
+                        opcode: current_byte,
+                        reg: reg_field,
+                    })
+                );
+            }
+
+            let destination = decode_rm_operand(&mut cursor, mod_val, rm, 1)?;
+
+            Instruction {
+                operation: Operation::Pop,
+                destination: Some(destination),
+                source: None,
+                third: None,
+                wide: true,
+                prefixes,
+                bytes: cursor.bytes(),
+            }
+        }
+
+        // Push/pop flags
+        v if v == 0b10011100 || v == 0b10011101 => {
+            let operation = if v & 0b1 == 0 { Operation::Pushf } else { Operation::Popf };
+
+            Instruction {
+                operation,
+                destination: None,
+                source: None,
+                third: None,
+                wide: true,
+                prefixes,
+                bytes: cursor.bytes(),
+            }
+        }
+
+        // String instructions (MOVS, CMPS, STOS, LODS, SCAS)
+        v if matches!(v >> 1, 0b1010010 | 0b1010011 | 0b1010101 | 0b1010110 | 0b1010111) => {
+            let operation = match v >> 1 {
+                0b1010010 => Operation::Movs,
+                0b1010011 => Operation::Cmps,
+                0b1010101 => Operation::Stos,
+                0b1010110 => Operation::Lods,
+                _ => Operation::Scas,
+            };
+
+            Instruction {
+                operation,
+                destination: None,
+                source: None,
+                third: None,
+                wide: v & 0b1 == 1,
+                prefixes,
+                bytes: cursor.bytes(),
+            }
+        }
+
+        // IN and OUT, with a fixed imm8 port or the port in DX
+        v if (v & 0b11110100) == 0b11100100 => {
+            let w = v & 0b1;
+            let accumulator = Operand::Register(if w == 1 { Register::AX } else { Register::AL });
+            let port = if (v >> 3) & 0b1 == 0 {
+                Operand::Immediate { value: cursor.next_u8()? as i16, wide: false }
+            } else {
+                Operand::Register(Register::DX)
+            };
+            let (operation, destination, source) = if (v >> 1) & 0b1 == 0 {
+                (Operation::In, accumulator, port)
+            } else {
+                (Operation::Out, port, accumulator)
+            };
+
+            Instruction {
+                operation,
+                destination: Some(destination),
+                source: Some(source),
+                third: None,
+                wide: w == 1,
+                prefixes,
+                bytes: cursor.bytes(),
+            }
+        }
+
+        // INT imm8, INT3, INTO and IRET
+        0b11001100..=0b11001111 => {
+            let (operation, destination) = match current_byte {
+                0b11001101 => {
+                    let vector = cursor.next_u8()?;
+                    (Operation::Int, Some(Operand::Immediate { value: vector as i16, wide: false }))
+                }
+                0b11001100 => (Operation::Int3, None),
+                0b11001110 => (Operation::Into, None),
+                _ => (Operation::Iret, None),
+            };
+
+            Instruction {
+                operation,
+                destination,
+                source: None,
+                third: None,
+                wide: true,
+                prefixes,
+                bytes: cursor.bytes(),
+            }
+        }
+
+        // Near relative CALL and JMP, short JMP
+        0b11101000 | 0b11101001 | 0b11101011 => {
+            let displacement = if current_byte == 0b11101011 {
+                cursor.next_u8()? as i8 as u16
+            } else {
+                cursor.next_u16()?
+            };
+            let target = cursor.next_ip().wrapping_add(displacement);
+            let operation = if current_byte == 0b11101000 { Operation::Call } else { Operation::Jmp };
+
+            Instruction {
+                operation,
+                destination: Some(Operand::Immediate { value: target as i16, wide: current_byte != 0b11101011 }),
+                source: None,
+                third: None,
+                // Width of the displacement, JMP has both a short and a near form
+                wide: current_byte != 0b11101011,
+                prefixes,
+                bytes: cursor.bytes(),
+            }
+        }
+
+        // Far direct CALL and JMP
+        0b10011010 | 0b11101010 => {
+            let offset = cursor.next_u16()?;
+            let segment = cursor.next_u16()?;
+            let operation = if current_byte == 0b10011010 {
+                Operation::CallFar
+            } else {
+                Operation::JmpFar
+            };
+
+            Instruction {
+                operation,
+                destination: Some(Operand::FarAddress { segment, offset }),
+                source: None,
+                third: None,
+                wide: true,
+                prefixes,
+                bytes: cursor.bytes(),
+            }
+        }
+
+        // Near and far RET, optionally releasing imm16 bytes of stack
+        v if (v & 0b11110110) == 0b11000010 => {
+            let operation = if (v >> 3) & 0b1 == 0 { Operation::Ret } else { Operation::Retf };
+            let destination = if v & 0b1 == 0 {
+                Some(Operand::Immediate { value: cursor.next_u16()? as i16, wide: true })
+            } else {
+                None
+            };
+
+            Instruction {
+                operation,
+                destination,
+                source: None,
+                third: None,
+                wide: true,
+                prefixes,
+                bytes: cursor.bytes(),
             }
         }
 
         // Conditional Jumps
-        v if (v >= 0b01110000 && v <= 0b01111111) || (v >= 0b11100000 && v <= 0b11100011) => {
+        v if (0b01110000..=0b01111111).contains(&v) || (0b11100000..=0b11100011).contains(&v) => {
             let operation = match v {
                 0b01110100 => Operation::Jz, // JE
                 0b01110101 => Operation::Jnz, // JNE
@@ -532,35 +1052,289 @@ pub fn decode(cpu: &mut Cpu) -> Instruction {
                 0b11100001 => Operation::Loopz,
                 0b11100000 => Operation::Loopnz,
                 0b11100011 => Operation::Jcxz,
-                _ => panic!("Unsupported jump opcode: {:08b}", v),
+                _ => {
+                    return Err(cursor.error(DecodeErrorReason::UnknownOpcode(v)));
+                }
             };
 
-            offset += 1;
-
             // Get offset for jump target (signed 8-bit displacement from next instruction)
-            let jump_offset = buffer[offset] as i8;
-            let target_offset = (offset as i16) + 1 + (jump_offset as i16);
-
-            offset += 1;
+            let jump_offset = cursor.next_u8()? as i8;
+            let target_offset = cursor.next_ip().wrapping_add(jump_offset as u16);
 
             // Create the instruction
-            let destination = Operand::Immediate(target_offset); // Relative target address
-            let instruction_bytes = buffer[start_offset..offset].to_vec();
+            let destination = Operand::Immediate { value: target_offset as i16, wide: false }; // Relative target address
 
             Instruction {
                 operation,
-                destination,
+                destination: Some(destination),
                 source: None, // No source operand for jumps
-                bytes: instruction_bytes,
+                third: None,
+                wide: false,
+                prefixes,
+                bytes: cursor.bytes(),
             }
         }
 
-        _ => {
+        // 80186: PUSHA, POPA and LEAVE
+        0b01100000 | 0b01100001 | 0b11001001 if model.is_80186() => {
+            let operation = match current_byte {
+                0b01100000 => Operation::Pusha,
+                0b01100001 => Operation::Popa,
+                _ => Operation::Leave,
+            };
+
+            Instruction {
+                operation,
+                destination: None,
+                source: None,
+                third: None,
+                wide: true,
+                prefixes,
+                bytes: cursor.bytes(),
+            }
+        }
+
+        // 80186: ENTER imm16 (frame size), imm8 (nesting level)
+        0b11001000 if model.is_80186() => {
+            let size = cursor.next_u16()?;
+            let level = cursor.next_u8()?;
+
+            Instruction {
+                operation: Operation::Enter,
+                destination: Some(Operand::Immediate { value: size as i16, wide: true }),
+                source: Some(Operand::Immediate { value: level as i16, wide: false }),
+                third: None,
+                wide: true,
+                prefixes,
+                bytes: cursor.bytes(),
+            }
+        }
+
+        // 80186: BOUND reg, mem, where mem holds the signed lower and upper limits
+        0b01100010 if model.is_80186() => {
+            let modrm = cursor.next_u8()?;
+            let mod_val = (modrm >> 6) & 0b11;
+            if mod_val == 0b11 {
+                return Err(cursor.error(DecodeErrorReason::MemoryOperandRequired(current_byte)));
+            }
+            let destination = Operand::Register(
+                register_from_index((modrm >> 3) & 0b111, 1).map_err(|reason| cursor.error(reason))?
+            );
+            let source = decode_rm_operand(&mut cursor, mod_val, modrm & 0b111, 1)?;
+
+            Instruction {
+                operation: Operation::Bound,
+                destination: Some(destination),
+                source: Some(source),
+                third: None,
+                wide: true,
+                prefixes,
+                bytes: cursor.bytes(),
+            }
+        }
+
+        // 80186: PUSH imm16 / imm8 sign extended
+        0b01101000 | 0b01101010 if model.is_80186() => {
+            let immediate = if current_byte == 0b01101000 {
+                cursor.next_u16()? as i16
+            } else {
+                cursor.next_u8()? as i8 as i16
+            };
+
+            Instruction {
+                operation: Operation::Push,
+                destination: Some(Operand::Immediate { value: immediate, wide: current_byte == 0b01101000 }),
+                source: None,
+                third: None,
+                wide: true,
+                prefixes,
+                bytes: cursor.bytes(),
+            }
+        }
+
+        // 80186: IMUL reg, r/m, imm16 / imm8 sign extended
+        0b01101001 | 0b01101011 if model.is_80186() => {
+            let modrm = cursor.next_u8()?;
+            let mod_val = (modrm >> 6) & 0b11;
+            let destination = Operand::Register(
+                register_from_index((modrm >> 3) & 0b111, 1).map_err(|reason| cursor.error(reason))?
+            );
+            let source = decode_rm_operand(&mut cursor, mod_val, modrm & 0b111, 1)?;
+            let immediate = if current_byte == 0b01101001 {
+                cursor.next_u16()? as i16
+            } else {
+                cursor.next_u8()? as i8 as i16
+            };
+
+            Instruction {
+                operation: Operation::Imul,
+                destination: Some(destination),
+                source: Some(source),
+                third: Some(Operand::Immediate { value: immediate, wide: current_byte == 0b01101001 }),
+                wide: true,
+                prefixes,
+                bytes: cursor.bytes(),
+            }
+        }
+
+        // 80186: INS and OUTS, string transfers between ES:DI or DS:SI and the port in DX
+        0b01101100..=0b01101111 if model.is_80186() => {
+            let operation = if current_byte & 0b10 == 0 { Operation::Ins } else { Operation::Outs };
+
+            Instruction {
+                operation,
+                destination: None,
+                source: None,
+                third: None,
+                wide: current_byte & 0b1 == 1,
+                prefixes,
+                bytes: cursor.bytes(),
+            }
+        }
+
+        // 80186: shifts and rotates by imm8
+        0b11000000 | 0b11000001 if model.is_80186() => {
+            let w = current_byte & 0b1;
+            let modrm = cursor.next_u8()?;
+            let mod_val = (modrm >> 6) & 0b11;
+            let reg_field = (modrm >> 3) & 0b111;
+            let operation = shift_from_reg(current_byte, reg_field).map_err(|reason| cursor.error(reason))?;
+            let destination = decode_rm_operand(&mut cursor, mod_val, modrm & 0b111, w)?;
+            let count = cursor.next_u8()?;
+
+            Instruction {
+                operation,
+                destination: Some(destination),
+                source: Some(Operand::Immediate { value: count as i16, wide: false }),
+                third: None,
+                wide: w == 1,
+                prefixes,
+                bytes: cursor.bytes(),
+            }
+        }
+
+        v => {
             // Unsupported instruction
-            panic!("Unsupported instruction at index {}: {:08b}", offset, current_byte);
+            return Err(cursor.error(DecodeErrorReason::UnknownOpcode(v)));
         }
     };
 
-    cpu.set_ip(offset);
-    instruction
+    Ok((instruction, cursor.offset))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_error(buffer: &[u8], offset: usize) -> DecodeError {
+        decode_at(buffer, offset, CpuModel::I8086).unwrap_err()
+    }
+
+    #[test]
+    fn unknown_opcode_reports_its_offset_and_bytes() {
+        let err = decode_error(&[0x90, 0xf1, 0x90], 1);
+        assert_eq!(err, DecodeError { offset: 1, bytes: vec![0xf1], reason: DecodeErrorReason::UnknownOpcode(0xf1) });
+        assert_eq!(err.to_string(), "unknown opcode 0xf1 at offset 0x1 (bytes: f1)");
+    }
+
+    #[test]
+    fn truncated_instruction_keeps_the_bytes_read() {
+        let err = decode_error(&[0xb8, 0x34], 0);
+        assert_eq!(err.reason, DecodeErrorReason::UnexpectedEnd);
+        assert_eq!(err.bytes, vec![0xb8, 0x34]);
+        // A displacement cut short
+        assert_eq!(decode_error(&[0x8b, 0x87, 0x05], 0).bytes, vec![0x8b, 0x87, 0x05]);
+    }
+
+    #[test]
+    fn unused_group_operation_is_an_error() {
+        let err = decode_error(&[0xf6, 0xc8], 0);
+        assert_eq!(err.reason, DecodeErrorReason::UnknownGroupOperation { opcode: 0xf6, reg: 1 });
+        assert_eq!(err.bytes, vec![0xf6, 0xc8]);
+    }
+
+    #[test]
+    fn decode_reports_the_physical_address() {
+        let mut cpu = Cpu::new(CpuModel::I8086);
+        cpu.registers.cs = 0x0100;
+        cpu.registers.ip = 0x0002;
+        cpu.memory[0x1002] = 0xf1;
+        let err = decode(&mut cpu).unwrap_err();
+        assert_eq!(err.offset, 0x1002);
+        // IP is left on the instruction that failed
+        assert_eq!(cpu.registers.ip, 0x0002);
+    }
+
+    #[test]
+    fn fetch_wraps_ip_within_the_code_segment() {
+        let mut cpu = Cpu::new(CpuModel::I8086);
+        cpu.registers.cs = 0x0100;
+        cpu.registers.ip = 0xffff;
+        // mov ax, 0x1234 split across the end of the segment
+        cpu.memory[0x10fff] = 0xb8;
+        cpu.memory[0x1000] = 0x34;
+        cpu.memory[0x1001] = 0x12;
+        let instruction = decode(&mut cpu).unwrap();
+        assert_eq!(instruction.to_string(), "mov ax, 4660");
+        assert_eq!(cpu.registers.ip, 0x0002);
+    }
+
+    fn decode_text(bytes: &[u8]) -> (Instruction, String) {
+        let (instruction, length) = decode_at(bytes, 0, CpuModel::I8086).unwrap();
+        assert_eq!(length, bytes.len());
+        let text = instruction.to_string();
+        (instruction, text)
+    }
+
+    #[test]
+    fn prefixes_are_kept_with_their_instruction() {
+        let (instruction, text) = decode_text(&[0x26, 0x8b, 0x07]);
+        assert_eq!(instruction.prefixes.segment, Some(Register::ES));
+        assert_eq!(text, "mov ax, [es:bx]");
+        let (instruction, text) = decode_text(&[0xf0, 0x87, 0x07]);
+        assert!(instruction.prefixes.lock);
+        assert_eq!(text, "lock xchg [bx], ax");
+        // Without a memory operand the override is a bare prefix
+        assert_eq!(decode_text(&[0x2e, 0xa4]).1, "cs movsb");
+    }
+
+    #[test]
+    fn rep_prefixes_follow_the_string_instruction() {
+        assert_eq!(decode_text(&[0xf3, 0xa5]).1, "rep movsw");
+        assert_eq!(decode_text(&[0xf3, 0xa6]).1, "repe cmpsb");
+        assert_eq!(decode_text(&[0xf2, 0xae]).1, "repne scasb");
+        let (instruction, _) = decode_text(&[0xf2, 0xae]);
+        assert_eq!(instruction.prefixes.repeat, Some(Repeat::Repne));
+    }
+
+    #[test]
+    fn later_prefixes_of_the_same_kind_win() {
+        let (instruction, text) = decode_text(&[0x26, 0x3e, 0x8b, 0x07]);
+        assert_eq!(instruction.prefixes.segment, Some(Register::DS));
+        assert_eq!(instruction.bytes, vec![0x26, 0x3e, 0x8b, 0x07]);
+        assert_eq!(text, "mov ax, [ds:bx]");
+    }
+
+    #[test]
+    fn extensions_decode_only_for_the_80186_models() {
+        let cases: [(&[u8], &str); 8] = [
+            (&[0x60], "pusha"),
+            (&[0xc8, 0x10, 0x00, 0x01], "enter 16, 1"),
+            (&[0x62, 0x07], "bound ax, [bx]"),
+            (&[0x6a, 0xfe], "push -2"),
+            (&[0x69, 0xc3, 0x10, 0x00], "imul ax, bx, strict word 16"),
+            (&[0xc0, 0x27, 0x02], "shl byte [bx], 2"),
+            (&[0x6d], "insw"),
+            (&[0xf3, 0x6e], "rep outsb"),
+        ];
+        for (bytes, text) in cases {
+            for model in [CpuModel::I80186, CpuModel::I80188] {
+                let (instruction, length) = decode_at(bytes, 0, model).unwrap();
+                assert_eq!((instruction.to_string().as_str(), length), (text, bytes.len()));
+            }
+            // On the 8086 the opcode after any REP prefix is unknown
+            let opcode = *bytes.iter().find(|byte| **byte != 0xf3).unwrap();
+            assert_eq!(decode_error(bytes, 0).reason, DecodeErrorReason::UnknownOpcode(opcode), "{}", text);
+        }
+    }
 }
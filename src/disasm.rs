@@ -0,0 +1,151 @@
+use std::collections::{ BTreeMap, BTreeSet };
+
+use crate::cpu::CpuModel;
+use crate::decoder::decode_at;
+use crate::instruction::{ FormatOptions, Instruction };
+
+// Disassembles a flat binary into NASM source that reassembles to the same bytes.
+// The model decides whether 80186 instructions are recognised.
+pub fn disassemble(buffer: &[u8], model: CpuModel) -> String {
+    // First pass: decode everything so jump targets can be given labels
+    let mut lines: Vec<(usize, Result<Instruction, u8>)> = Vec::new();
+    let mut offset = 0;
+    while offset < buffer.len() {
+        match decode_at(buffer, offset, model) {
+            Ok((instruction, next_offset)) => {
+                lines.push((offset, Ok(instruction)));
+                offset = next_offset;
+            }
+            Err(_) => {
+                // Not an instruction we understand, keep the byte as data
+                lines.push((offset, Err(buffer[offset])));
+                offset += 1;
+            }
+        }
+    }
+
+    let boundaries: BTreeSet<usize> = lines.iter().map(|(address, _)| *address).collect();
+    let mut labels: BTreeMap<usize, String> = BTreeMap::new();
+    for (_, line) in &lines {
+        if let Some(target) = line.as_ref().ok().and_then(Instruction::jump_target) {
+            let target = target as usize;
+            if boundaries.contains(&target) {
+                labels.entry(target).or_default();
+            }
+        }
+    }
+    for (count, name) in labels.values_mut().enumerate() {
+        *name = format!("label_{}", count);
+    }
+
+    // Second pass: emit the source
+    let mut output = String::from("bits 16\n\n");
+    for (address, line) in &lines {
+        if let Some(name) = labels.get(address) {
+            output.push_str(&format!("{}:\n", name));
+        }
+        match line {
+            Ok(instruction) if !nasm_encodes(instruction) => {
+                // NASM would pick another encoding, keep the bytes and show the instruction alongside
+                let bytes: Vec<String> = instruction.bytes.iter().map(|byte| format!("{:#04x}", byte)).collect();
+                output.push_str(&format!("db {} ; ", bytes.join(", ")));
+                output.push_str(&format_instruction(instruction, *address, &labels));
+            }
+            Ok(instruction) => {
+                output.push_str(&format_instruction(instruction, *address, &labels));
+            }
+            Err(byte) => {
+                output.push_str(&format!("db {:#04x}", byte));
+            }
+        }
+        output.push('\n');
+    }
+    output
+}
+
+fn format_instruction(
+    instruction: &Instruction,
+    address: usize,
+    labels: &BTreeMap<usize, String>
+) -> String {
+    // Targets that aren't instruction boundaries stay as absolute addresses
+    let label = instruction.jump_target().and_then(|target| labels.get(&(target as usize)));
+    instruction
+        .display(address, FormatOptions::default())
+        .with_label(label.map(String::as_str))
+        .to_string()
+}
+
+// Whether NASM assembles the printed instruction back to the same bytes. Displacement and immediate
+// sizes are spelled out by the formatter; what's left are encodings NASM never picks for the same text.
+fn nasm_encodes(instruction: &Instruction) -> bool {
+    let bytes = &instruction.bytes;
+    let prefix_count = bytes
+        .iter()
+        .take_while(|byte| matches!(byte, 0x26 | 0x2e | 0x36 | 0x3e | 0xf0 | 0xf2 | 0xf3))
+        .count();
+    // NASM orders prefixes its own way, and repeated ones don't survive decoding
+    if prefix_count > 1 {
+        return false;
+    }
+    let opcode = bytes[prefix_count];
+    let modrm = bytes.get(prefix_count + 1).copied().unwrap_or(0);
+    let register_mode = modrm >> 6 == 0b11;
+    let reg = (modrm >> 3) & 0b111;
+    let rm = modrm & 0b111;
+    match opcode {
+        // Register to register with d = 1, NASM uses the d = 0 form
+        0x00..=0x3f if opcode & 0b111 < 4 && opcode & 0b10 != 0 => !register_mode,
+        0x8a | 0x8b => !register_mode && (modrm & 0b11000111 != 0b00000110 || reg != 0),
+        // MOV between the accumulator and a direct address has its own opcodes
+        0x88 | 0x89 => modrm & 0b11000111 != 0b00000110 || reg != 0,
+        // Immediate to AL or AX has its own opcodes
+        0x80 | 0x81 => !(register_mode && rm == 0),
+        0x82 => false,
+        0xf6 | 0xf7 if reg == 0 => !(register_mode && rm == 0),
+        // Register forms that have a one byte encoding
+        0xc6 | 0xc7 | 0x8f | 0x86 | 0x87 => !register_mode,
+        0xff if matches!(reg, 0 | 1 | 6) => !register_mode,
+        // A count of 1 is encoded with 0xd0 and 0xd1
+        0xc0 | 0xc1 => bytes.last() != Some(&1),
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn disassemble_8086(bytes: &[u8]) -> String {
+        disassemble(bytes, CpuModel::I8086).trim_start_matches("bits 16\n\n").to_string()
+    }
+
+    #[test]
+    fn displacement_width_is_spelled_out() {
+        assert_eq!(disassemble_8086(&[0x8b, 0x40, 0x00]), "mov ax, [byte bx + si + 0]\n");
+        assert_eq!(disassemble_8086(&[0x8b, 0x87, 0x05, 0x00]), "mov ax, [word bx + 5]\n");
+        assert_eq!(disassemble_8086(&[0x8b, 0x46, 0x00]), "mov ax, [bp]\n");
+        assert_eq!(disassemble_8086(&[0x8b, 0x86, 0x00, 0x00]), "mov ax, [word bp + 0]\n");
+        assert_eq!(disassemble_8086(&[0x8b, 0x87, 0x2c, 0x01]), "mov ax, [bx + 300]\n");
+    }
+
+    #[test]
+    fn word_immediate_with_a_byte_form_is_strict() {
+        assert_eq!(disassemble_8086(&[0x81, 0xc3, 0x05, 0x00]), "add bx, strict word 5\n");
+        assert_eq!(disassemble_8086(&[0x83, 0xc3, 0x05]), "add bx, 5\n");
+        assert_eq!(disassemble_8086(&[0x05, 0x05, 0x00]), "add ax, strict word 5\n");
+        // MOV has no byte form
+        assert_eq!(disassemble_8086(&[0xbb, 0x05, 0x00]), "mov bx, 5\n");
+        let source = disassemble(&[0x68, 0x05, 0x00, 0x69, 0xc3, 0x05, 0x00], CpuModel::I80186);
+        assert_eq!(source, "bits 16\n\npush strict word 5\nimul ax, bx, strict word 5\n");
+    }
+
+    #[test]
+    fn encodings_nasm_does_not_pick_are_kept_as_data() {
+        assert_eq!(disassemble_8086(&[0x8b, 0x06, 0x10, 0x00]), "db 0x8b, 0x06, 0x10, 0x00 ; mov ax, [16]\n");
+        assert_eq!(disassemble_8086(&[0x8b, 0xd8]), "db 0x8b, 0xd8 ; mov bx, ax\n");
+        assert_eq!(disassemble_8086(&[0x81, 0xc0, 0x34, 0x12]), "db 0x81, 0xc0, 0x34, 0x12 ; add ax, 4660\n");
+        assert_eq!(disassemble_8086(&[0xa1, 0x10, 0x00]), "mov ax, [16]\n");
+        assert_eq!(disassemble_8086(&[0x89, 0xd8]), "mov ax, bx\n");
+    }
+}
@@ -1,4 +1,6 @@
-#[derive(Debug, Clone)]
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Register {
     AL,
     AH,
@@ -16,6 +18,10 @@ pub enum Register {
     BP,
     SI,
     DI,
+    ES,
+    CS,
+    SS,
+    DS,
 }
 
 #[derive(Debug)]
@@ -25,16 +31,97 @@ pub enum AddressingMode {
     Memory {
         base: Option<Register>,
         index: Option<Register>,
-        displacement: Option<i16>,
+        displacement: Option<Displacement>,
     },
 }
 
+// A displacement as it was encoded: a sign-extended byte, or a full word
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Displacement {
+    pub value: i16,
+    pub wide: bool,
+}
+
 #[derive(Debug)]
 pub enum Operation {
     Mov,
     Add,
+    Adc, // Add with carry
     Sub,
+    Sbb, // Subtract with borrow
     Cmp,
+    And,
+    Or,
+    Xor,
+    Not,
+    Test, // AND that only sets flags
+    Inc,
+    Dec,
+    Neg, // Two's complement negation
+    Mul, // Unsigned multiply into AX or DX:AX
+    Imul, // Signed multiply into AX or DX:AX
+    Div, // Unsigned divide of AX or DX:AX
+    Idiv, // Signed divide of AX or DX:AX
+    Rol, // Rotate left
+    Ror, // Rotate right
+    Rcl, // Rotate left through carry
+    Rcr, // Rotate right through carry
+    Shl, // Shift left (same as SAL)
+    Shr, // Logical shift right
+    Sar, // Arithmetic shift right
+    Push,
+    Pop,
+    Pushf, // Push the FLAGS register
+    Popf, // Pop the FLAGS register
+    Jmp, // Near jump, relative or indirect
+    JmpFar, // Far jump to a new CS:IP
+    Call, // Near call, relative or indirect
+    CallFar, // Far call to a new CS:IP
+    Ret, // Near return
+    Retf, // Far return
+    Movs, // Move string element from DS:SI to ES:DI
+    Cmps, // Compare DS:SI with ES:DI
+    Scas, // Compare the accumulator with ES:DI
+    Lods, // Load string element at DS:SI into the accumulator
+    Stos, // Store the accumulator at ES:DI
+    Int, // Software interrupt through the IVT
+    Int3, // Breakpoint, interrupt 3
+    Into, // Interrupt 4 if OF is set
+    Iret, // Return from an interrupt handler
+    In, // Read from an I/O port
+    Out, // Write to an I/O port
+    Lea, // Load the offset of a memory operand
+    Lds, // Load a far pointer into a register and DS
+    Les, // Load a far pointer into a register and ES
+    Xchg,
+    Xlat, // AL = [BX + AL]
+    Cbw, // Sign-extend AL into AX
+    Cwd, // Sign-extend AX into DX:AX
+    Nop,
+    Clc, // Clear carry
+    Stc, // Set carry
+    Cmc, // Complement carry
+    Cld, // Clear direction
+    Std, // Set direction
+    Cli, // Clear interrupt enable
+    Sti, // Set interrupt enable
+    Lahf, // Load AH from the low byte of FLAGS
+    Sahf, // Store AH into SF, ZF, AF, PF and CF
+    Hlt, // Stop until an interrupt arrives
+    Wait, // Wait for the TEST pin (no coprocessor, so never waits)
+    Daa, // Decimal adjust after addition
+    Das, // Decimal adjust after subtraction
+    Aaa, // ASCII adjust after addition
+    Aas, // ASCII adjust after subtraction
+    Aam, // ASCII adjust after multiply, with an immediate base
+    Aad, // ASCII adjust before division, with an immediate base
+    Pusha, // 80186: push all general registers
+    Popa, // 80186: pop all general registers
+    Enter, // 80186: set up a stack frame
+    Leave, // 80186: tear down a stack frame
+    Bound, // 80186: interrupt 5 if a register is outside the limits in memory
+    Ins, // 80186: input string element from port DX to ES:DI
+    Outs, // 80186: output string element at DS:SI to port DX
     Jnz, // Jump not zero/not equal
     Jz, // Jump if zero/equal
     Jl, // Jump if less
@@ -62,13 +149,589 @@ pub enum Operation {
 pub enum Operand {
     Register(Register),
     Memory(AddressingMode),
-    Immediate(i16),
+    Immediate {
+        value: i16,
+        wide: bool, // Encoded as a full word, rather than a byte (sign-extended for 0x83, 0x6a and 0x6b)
+    },
+    FarAddress {
+        segment: u16,
+        offset: u16,
+    }, // Target of a direct far JMP or CALL
 }
 
 #[derive(Debug)]
 pub struct Instruction {
     pub operation: Operation,
-    pub destination: Operand,
+    pub destination: Option<Operand>, // None for instructions without operands
     pub source: Option<Operand>,
-    pub bytes: Vec<u8>,
+    pub third: Option<Operand>, // Only IMUL reg, r/m, imm has a third operand
+    pub wide: bool, // Word (true) or byte (false) operand width
+    pub prefixes: Prefixes,
+    pub bytes: Vec<u8>, // Encoded bytes, prefixes included
+}
+
+// REP prefixes. F3 is REP/REPE/REPZ, F2 is REPNE/REPNZ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Repeat {
+    Rep,
+    Repne,
+}
+
+// Prefix bytes that preceded an instruction
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Prefixes {
+    pub segment: Option<Register>, // Segment override for the memory operand
+    pub lock: bool,
+    pub repeat: Option<Repeat>,
+}
+
+// Controls how an instruction is rendered as assembly text
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FormatOptions {
+    pub hex: bool, // Immediates, displacements and addresses in hex instead of decimal
+    pub uppercase: bool, // Mnemonics, registers and size keywords in upper case
+    pub show_bytes: bool, // Prefix each instruction with its encoded bytes
+    pub show_address: bool, // Prefix each instruction with its address
+}
+
+impl Register {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Register::AL => "al",
+            Register::AH => "ah",
+            Register::BL => "bl",
+            Register::BH => "bh",
+            Register::CL => "cl",
+            Register::CH => "ch",
+            Register::DL => "dl",
+            Register::DH => "dh",
+            Register::AX => "ax",
+            Register::BX => "bx",
+            Register::CX => "cx",
+            Register::DX => "dx",
+            Register::SP => "sp",
+            Register::BP => "bp",
+            Register::SI => "si",
+            Register::DI => "di",
+            Register::ES => "es",
+            Register::CS => "cs",
+            Register::SS => "ss",
+            Register::DS => "ds",
+        }
+    }
+}
+
+impl Operation {
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Operation::Mov => "mov",
+            Operation::Add => "add",
+            Operation::Adc => "adc",
+            Operation::Sub => "sub",
+            Operation::Sbb => "sbb",
+            Operation::Cmp => "cmp",
+            Operation::And => "and",
+            Operation::Or => "or",
+            Operation::Xor => "xor",
+            Operation::Not => "not",
+            Operation::Test => "test",
+            Operation::Inc => "inc",
+            Operation::Dec => "dec",
+            Operation::Neg => "neg",
+            Operation::Mul => "mul",
+            Operation::Imul => "imul",
+            Operation::Div => "div",
+            Operation::Idiv => "idiv",
+            Operation::Rol => "rol",
+            Operation::Ror => "ror",
+            Operation::Rcl => "rcl",
+            Operation::Rcr => "rcr",
+            Operation::Shl => "shl",
+            Operation::Shr => "shr",
+            Operation::Sar => "sar",
+            Operation::Push => "push",
+            Operation::Pop => "pop",
+            Operation::Pushf => "pushf",
+            Operation::Popf => "popf",
+            Operation::Jmp | Operation::JmpFar => "jmp",
+            Operation::Call | Operation::CallFar => "call",
+            Operation::Ret => "ret",
+            Operation::Retf => "retf",
+            Operation::Movs => "movs",
+            Operation::Cmps => "cmps",
+            Operation::Scas => "scas",
+            Operation::Lods => "lods",
+            Operation::Stos => "stos",
+            Operation::Int => "int",
+            Operation::Int3 => "int3",
+            Operation::Into => "into",
+            Operation::Iret => "iret",
+            Operation::In => "in",
+            Operation::Out => "out",
+            Operation::Lea => "lea",
+            Operation::Lds => "lds",
+            Operation::Les => "les",
+            Operation::Xchg => "xchg",
+            Operation::Xlat => "xlatb",
+            Operation::Cbw => "cbw",
+            Operation::Cwd => "cwd",
+            Operation::Nop => "nop",
+            Operation::Clc => "clc",
+            Operation::Stc => "stc",
+            Operation::Cmc => "cmc",
+            Operation::Cld => "cld",
+            Operation::Std => "std",
+            Operation::Cli => "cli",
+            Operation::Sti => "sti",
+            Operation::Lahf => "lahf",
+            Operation::Sahf => "sahf",
+            Operation::Hlt => "hlt",
+            Operation::Wait => "wait",
+            Operation::Daa => "daa",
+            Operation::Das => "das",
+            Operation::Aaa => "aaa",
+            Operation::Aas => "aas",
+            Operation::Aam => "aam",
+            Operation::Aad => "aad",
+            Operation::Pusha => "pusha",
+            Operation::Popa => "popa",
+            Operation::Enter => "enter",
+            Operation::Leave => "leave",
+            Operation::Bound => "bound",
+            Operation::Ins => "ins",
+            Operation::Outs => "outs",
+            Operation::Jnz => "jne",
+            Operation::Jz => "je",
+            Operation::Jl => "jl",
+            Operation::Jle => "jle",
+            Operation::Jb => "jb",
+            Operation::Jbe => "jbe",
+            Operation::Jp => "jp",
+            Operation::Jo => "jo",
+            Operation::Js => "js",
+            Operation::Jne => "jne",
+            Operation::Jnl => "jnl",
+            Operation::Jg => "jg",
+            Operation::Jnb => "jnb",
+            Operation::Ja => "ja",
+            Operation::Jnp => "jnp",
+            Operation::Jno => "jno",
+            Operation::Jns => "jns",
+            Operation::Loop => "loop",
+            Operation::Loopz => "loopz",
+            Operation::Loopnz => "loopnz",
+            Operation::Jcxz => "jcxz",
+        }
+    }
+
+    // String instructions, whose mnemonic carries the b/w width suffix
+    pub fn is_string(&self) -> bool {
+        matches!(
+            self,
+            Operation::Movs |
+                Operation::Cmps |
+                Operation::Scas |
+                Operation::Lods |
+                Operation::Stos |
+                Operation::Ins |
+                Operation::Outs
+        )
+    }
+
+    pub fn is_shift(&self) -> bool {
+        matches!(
+            self,
+            Operation::Rol |
+                Operation::Ror |
+                Operation::Rcl |
+                Operation::Rcr |
+                Operation::Shl |
+                Operation::Shr |
+                Operation::Sar
+        )
+    }
+
+    // Word immediates of these also have a sign-extended byte encoding (0x83, 0x6a, 0x6b)
+    pub fn has_short_immediate(&self) -> bool {
+        matches!(
+            self,
+            Operation::Add |
+                Operation::Or |
+                Operation::Adc |
+                Operation::Sbb |
+                Operation::And |
+                Operation::Sub |
+                Operation::Xor |
+                Operation::Cmp |
+                Operation::Push |
+                Operation::Imul
+        )
+    }
+
+    // Jumps, calls and loops whose destination can be an IP-relative target
+    pub fn is_relative_jump(&self) -> bool {
+        matches!(
+            self,
+            Operation::Jmp |
+                Operation::Call |
+                Operation::Jnz |
+                Operation::Jz |
+                Operation::Jl |
+                Operation::Jle |
+                Operation::Jb |
+                Operation::Jbe |
+                Operation::Jp |
+                Operation::Jo |
+                Operation::Js |
+                Operation::Jne |
+                Operation::Jnl |
+                Operation::Jg |
+                Operation::Jnb |
+                Operation::Ja |
+                Operation::Jnp |
+                Operation::Jno |
+                Operation::Jns |
+                Operation::Loop |
+                Operation::Loopz |
+                Operation::Loopnz |
+                Operation::Jcxz
+        )
+    }
+}
+
+impl Instruction {
+    // Address a relative jump transfers control to
+    pub fn jump_target(&self) -> Option<u16> {
+        match (&self.operation, &self.destination) {
+            (operation, Some(Operand::Immediate { value, .. })) if operation.is_relative_jump() => {
+                Some(*value as u16)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn display(&self, address: usize, options: FormatOptions) -> InstructionDisplay<'_> {
+        InstructionDisplay { instruction: self, address, options, label: None }
+    }
+
+    // Memory operands need an explicit size when no register operand implies it.
+    // A shift count in CL says nothing about the size of the shifted operand.
+    fn needs_size(&self) -> bool {
+        !matches!(self.destination, Some(Operand::Register(_))) &&
+            (self.operation.is_shift() || !matches!(self.source, Some(Operand::Register(_))))
+    }
+}
+
+// An instruction paired with its address and the options to render it with
+pub struct InstructionDisplay<'a> {
+    instruction: &'a Instruction,
+    address: usize,
+    options: FormatOptions,
+    label: Option<&'a str>, // Printed in place of a relative jump's target address
+}
+
+impl<'a> InstructionDisplay<'a> {
+    pub fn with_label(mut self, label: Option<&'a str>) -> Self {
+        self.label = label;
+        self
+    }
+}
+
+fn write_keyword(f: &mut fmt::Formatter<'_>, keyword: &str, options: &FormatOptions) -> fmt::Result {
+    if options.uppercase { write!(f, "{}", keyword.to_uppercase()) } else { write!(f, "{}", keyword) }
+}
+
+fn write_number(f: &mut fmt::Formatter<'_>, val: i32, options: &FormatOptions) -> fmt::Result {
+    match (options.hex, options.uppercase) {
+        (false, _) => write!(f, "{}", val),
+        (true, false) => write!(f, "{:#x}", val),
+        (true, true) => write!(f, "{:#X}", val),
+    }
+}
+
+fn write_address(
+    f: &mut fmt::Formatter<'_>,
+    address: &AddressingMode,
+    segment: Option<Register>, // Segment override, written inside the brackets as NASM wants it
+    options: &FormatOptions
+) -> fmt::Result {
+    let write_segment = |f: &mut fmt::Formatter<'_>| -> fmt::Result {
+        match segment {
+            Some(segment) => {
+                write_keyword(f, segment.name(), options)?;
+                write!(f, ":")
+            }
+            None => Ok(()),
+        }
+    };
+    match address {
+        AddressingMode::Direct(addr) => {
+            write!(f, "[")?;
+            write_segment(f)?;
+            write_number(f, *addr as u16 as i32, options)?;
+            write!(f, "]")
+        }
+        AddressingMode::Register(reg) => write_keyword(f, reg.name(), options),
+        AddressingMode::Memory { base, index, displacement } => {
+            // NASM encodes the shortest displacement that holds the value: none for 0 (except for [bp],
+            // which has no such form), then a byte, then a word. Any other choice has to be spelled out.
+            let bp_only = matches!((base, index), (Some(Register::BP), None));
+            let forced_size = match displacement {
+                Some(Displacement { value, wide: true }) if (-128..=127).contains(value) => Some("word "),
+                Some(Displacement { value: 0, wide: false }) if !bp_only => Some("byte "),
+                _ => None,
+            };
+            write!(f, "[")?;
+            if let Some(size) = forced_size {
+                write_keyword(f, size, options)?;
+            }
+            write_segment(f)?;
+            for (position, reg) in [base, index].into_iter().flatten().enumerate() {
+                if position > 0 {
+                    write!(f, " + ")?;
+                }
+                write_keyword(f, reg.name(), options)?;
+            }
+            match displacement {
+                Some(disp) if disp.value < 0 => {
+                    write!(f, " - ")?;
+                    write_number(f, -(disp.value as i32), options)?;
+                }
+                Some(disp) if disp.value > 0 || forced_size.is_some() => {
+                    write!(f, " + ")?;
+                    write_number(f, disp.value as i32, options)?;
+                }
+                _ => {}
+            }
+            write!(f, "]")
+        }
+    }
+}
+
+fn write_operand(
+    f: &mut fmt::Formatter<'_>,
+    operand: &Operand,
+    size: Option<bool>, // Some(wide) when the width must be spelled out
+    segment: Option<Register>, // Segment override, only shown on memory operands
+    options: &FormatOptions
+) -> fmt::Result {
+    match operand {
+        Operand::Register(reg) => write_keyword(f, reg.name(), options),
+        Operand::Immediate { value, .. } => {
+            // Decimal immediates are signed, hex ones show the raw bits of the operand width
+            let val = match (size, options.hex) {
+                (Some(false), false) => *value as i8 as i32,
+                (Some(false), true) => *value as u8 as i32,
+                (_, false) => *value as i32,
+                (_, true) => *value as u16 as i32,
+            };
+            write_number(f, val, options)
+        }
+        Operand::Memory(addr) => {
+            match size {
+                Some(true) => write_keyword(f, "word ", options)?,
+                Some(false) => write_keyword(f, "byte ", options)?,
+                None => {}
+            }
+            write_address(f, addr, segment, options)
+        }
+        Operand::FarAddress { segment, offset } => {
+            write_number(f, *segment as i32, options)?;
+            write!(f, ":")?;
+            write_number(f, *offset as i32, options)
+        }
+    }
+}
+
+impl fmt::Display for InstructionDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let instruction = self.instruction;
+        let options = &self.options;
+
+        if options.show_address {
+            write!(f, "{:04x}  ", self.address)?;
+        }
+        if options.show_bytes {
+            let bytes: Vec<String> = instruction.bytes
+                .iter()
+                .map(|byte| if options.uppercase { format!("{:02X}", byte) } else { format!("{:02x}", byte) })
+                .collect();
+            write!(f, "{:<20}", bytes.join(" "))?;
+        }
+
+        let prefixes = &instruction.prefixes;
+        if prefixes.lock {
+            write_keyword(f, "lock ", options)?;
+        }
+        let compares = matches!(instruction.operation, Operation::Cmps | Operation::Scas);
+        match prefixes.repeat {
+            Some(Repeat::Rep) if compares => write_keyword(f, "repe ", options)?,
+            Some(Repeat::Rep) => write_keyword(f, "rep ", options)?,
+            Some(Repeat::Repne) => write_keyword(f, "repne ", options)?,
+            None => {}
+        }
+        // An override on an instruction without a memory operand can only be shown as a bare prefix
+        let has_memory_operand = [&instruction.destination, &instruction.source]
+            .iter()
+            .any(|operand| matches!(operand, Some(Operand::Memory(_))));
+        if let (Some(segment), false) = (prefixes.segment, has_memory_operand) {
+            write_keyword(f, segment.name(), options)?;
+            write!(f, " ")?;
+        }
+        write_keyword(f, instruction.operation.mnemonic(), options)?;
+        if instruction.operation.is_string() {
+            write_keyword(f, if instruction.wide { "w" } else { "b" }, options)?;
+        }
+        if let Some(target) = instruction.jump_target() {
+            write!(f, " ")?;
+            // NASM picks the shortest JMP that reaches, so the encoding has to be spelled out to reassemble the same bytes
+            if let Operation::Jmp = instruction.operation {
+                write_keyword(f, if instruction.wide { "near " } else { "short " }, options)?;
+            }
+            return match self.label {
+                Some(label) => write!(f, "{}", label),
+                None => write_number(f, target as i32, options),
+            };
+        }
+
+        let unsigned_byte = matches!(
+            instruction.operation,
+            Operation::In | Operation::Out | Operation::Int | Operation::Aam | Operation::Aad
+        );
+        // Register operands carry their own width, everything else follows the instruction's
+        let width = |operand: &Operand| -> Option<bool> {
+            match operand {
+                Operand::Memory(_) if !instruction.needs_size() => None,
+                // Port numbers, interrupt vectors and AAM/AAD bases are unsigned bytes. Printing them at word
+                // width keeps values above 0x7f from showing up as negative.
+                Operand::Immediate { .. } if unsigned_byte => Some(true),
+                _ => Some(instruction.wide),
+            }
+        };
+        // NASM picks the byte form for small word immediates when there is one, so the word form has to be forced
+        let write_strict = |f: &mut fmt::Formatter<'_>, operand: &Operand| -> fmt::Result {
+            match operand {
+                Operand::Immediate { value, wide: true }
+                    if instruction.operation.has_short_immediate() && (-128..=127).contains(value) => {
+                    write_keyword(f, "strict word ", options)
+                }
+                _ => Ok(()),
+            }
+        };
+        if let Some(destination) = &instruction.destination {
+            write!(f, " ")?;
+            match (&instruction.operation, destination) {
+                // Far pointers in memory are marked "far" instead of with their size
+                (Operation::JmpFar | Operation::CallFar, Operand::Memory(_)) => {
+                    write_keyword(f, "far ", options)?;
+                    write_operand(f, destination, None, prefixes.segment, options)?;
+                }
+                _ => {
+                    write_strict(f, destination)?;
+                    write_operand(f, destination, width(destination), prefixes.segment, options)?;
+                }
+            }
+        }
+        if let Some(source) = &instruction.source {
+            write!(f, ", ")?;
+            write_strict(f, source)?;
+            write_operand(f, source, width(source), prefixes.segment, options)?;
+        }
+        if let Some(third) = &instruction.third {
+            write!(f, ", ")?;
+            write_strict(f, third)?;
+            write_operand(f, third, width(third), prefixes.segment, options)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.display(0, FormatOptions::default()))
+    }
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.mnemonic())
+    }
+}
+
+impl fmt::Display for AddressingMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_address(f, self, None, &FormatOptions::default())
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_operand(f, self, None, None, &FormatOptions::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::CpuModel;
+    use crate::decoder::decode_at;
+
+    fn format(bytes: &[u8], address: usize, options: FormatOptions) -> String {
+        let (instruction, _) = decode_at(bytes, 0, CpuModel::I8086).unwrap();
+        instruction.display(address, options).to_string()
+    }
+
+    #[test]
+    fn default_is_lower_case_decimal() {
+        // mov word [bx + di - 37], 347
+        let bytes = [0xc7, 0x41, 0xdb, 0x5b, 0x01];
+        assert_eq!(format(&bytes, 0, FormatOptions::default()), "mov word [bx + di - 37], 347");
+    }
+
+    #[test]
+    fn hex_shows_numbers_in_hex() {
+        let options = FormatOptions { hex: true, ..FormatOptions::default() };
+        assert_eq!(format(&[0xc7, 0x41, 0xdb, 0x5b, 0x01], 0, options), "mov word [bx + di - 0x25], 0x15b");
+        // Negative immediates show the raw bits of the operand width
+        assert_eq!(format(&[0xb1, 0xf4], 0, options), "mov cl, 0xf4");
+        assert_eq!(format(&[0xa1, 0xfb, 0x09], 0, options), "mov ax, [0x9fb]");
+    }
+
+    #[test]
+    fn interrupt_vectors_are_unsigned() {
+        assert_eq!(format(&[0xcd, 0x80], 0, FormatOptions::default()), "int 128");
+        assert_eq!(format(&[0xcd, 0xff], 0, FormatOptions { hex: true, ..FormatOptions::default() }), "int 0xff");
+    }
+
+    #[test]
+    fn aam_and_aad_bases_are_unsigned() {
+        assert_eq!(format(&[0xd4, 0x0a], 0, FormatOptions::default()), "aam 10");
+        assert_eq!(format(&[0xd5, 0xc8], 0, FormatOptions::default()), "aad 200");
+    }
+
+    #[test]
+    fn uppercase_covers_keywords_and_hex_digits() {
+        let options = FormatOptions { uppercase: true, ..FormatOptions::default() };
+        assert_eq!(format(&[0xc7, 0x41, 0xdb, 0x5b, 0x01], 0, options), "MOV WORD [BX + DI - 37], 347");
+        let options = FormatOptions { hex: true, ..options };
+        assert_eq!(format(&[0x26, 0x8b, 0x47, 0x2f], 0, options), "MOV AX, [ES:BX + 0x2F]");
+    }
+
+    #[test]
+    fn bytes_and_address_are_prefixed() {
+        let options = FormatOptions { show_bytes: true, ..FormatOptions::default() };
+        assert_eq!(format(&[0x89, 0xd8], 0, options), format!("{:<20}mov ax, bx", "89 d8"));
+        let options = FormatOptions { show_address: true, ..options };
+        assert_eq!(format(&[0x89, 0xd8], 0x1a, options), format!("001a  {:<20}mov ax, bx", "89 d8"));
+        let options = FormatOptions { show_bytes: false, ..options };
+        assert_eq!(format(&[0x89, 0xd8], 0x1a, options), "001a  mov ax, bx");
+        let options = FormatOptions { show_bytes: true, show_address: false, uppercase: true, ..options };
+        assert_eq!(format(&[0x8b, 0xda], 0, options), format!("{:<20}MOV BX, DX", "8B DA"));
+    }
 }
@@ -0,0 +1,73 @@
+use std::ops::RangeInclusive;
+
+// A device (or set of devices) answering IN and OUT. Ports it doesn't decode should read 0xff.
+pub trait IoBus {
+    fn read(&mut self, port: u16) -> u8;
+    fn write(&mut self, port: u16, val: u8);
+
+    // Word transfers go to `port` and `port + 1`, low byte first
+    fn read_word(&mut self, port: u16) -> u16 {
+        let low_byte = self.read(port) as u16;
+        let high_byte = self.read(port.wrapping_add(1)) as u16;
+        (high_byte << 8) | low_byte
+    }
+
+    fn write_word(&mut self, port: u16, val: u16) {
+        self.write(port, val as u8);
+        self.write(port.wrapping_add(1), (val >> 8) as u8);
+    }
+}
+
+// Routes each port to the device mapped over it. Ports no device claims read 0xff and drop writes.
+#[derive(Default)]
+pub struct PortMap {
+    devices: Vec<(RangeInclusive<u16>, Box<dyn IoBus>)>,
+}
+
+impl PortMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Where ranges overlap, the device mapped last answers
+    pub fn map(&mut self, ports: RangeInclusive<u16>, device: Box<dyn IoBus>) {
+        self.devices.push((ports, device));
+    }
+
+    fn device_at(&self, port: u16) -> Option<usize> {
+        self.devices.iter().rposition(|(ports, _)| ports.contains(&port))
+    }
+}
+
+impl IoBus for PortMap {
+    fn read(&mut self, port: u16) -> u8 {
+        match self.device_at(port) {
+            Some(device) => self.devices[device].1.read(port),
+            None => 0xff,
+        }
+    }
+
+    fn write(&mut self, port: u16, val: u8) {
+        if let Some(device) = self.device_at(port) {
+            self.devices[device].1.write(port, val);
+        }
+    }
+
+    // A word within one device is a single transfer to it, one straddling two devices is split in bytes
+    fn read_word(&mut self, port: u16) -> u16 {
+        match (self.device_at(port), self.device_at(port.wrapping_add(1))) {
+            (Some(low), Some(high)) if low == high => self.devices[low].1.read_word(port),
+            _ => (self.read(port.wrapping_add(1)) as u16) << 8 | self.read(port) as u16,
+        }
+    }
+
+    fn write_word(&mut self, port: u16, val: u16) {
+        match (self.device_at(port), self.device_at(port.wrapping_add(1))) {
+            (Some(low), Some(high)) if low == high => self.devices[low].1.write_word(port, val),
+            _ => {
+                self.write(port, val as u8);
+                self.write(port.wrapping_add(1), (val >> 8) as u8);
+            }
+        }
+    }
+}
@@ -0,0 +1,8 @@
+pub mod alu;
+pub mod clocks;
+pub mod cpu;
+pub mod decoder;
+pub mod disasm;
+pub mod instruction;
+pub mod io;
+pub mod trace;
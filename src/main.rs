@@ -1,8 +1,8 @@
-mod decoder;
-mod instruction;
-mod cpu;
-use cpu::Cpu;
-use decoder::decode;
+use sim_8086::clocks;
+use sim_8086::cpu::{ Cpu, CpuModel };
+use sim_8086::decoder::decode;
+use sim_8086::disasm::disassemble;
+use sim_8086::trace::{ self, Snapshot };
 use std::env;
 use std::fs::File;
 use std::io::{ Error, Read };
@@ -10,22 +10,95 @@ use std::process::exit;
 
 fn main() {
     let env: Vec<String> = env::args().collect();
-    if env.len() != 2 {
-        println!("Please provide the binary files to simulate");
-        exit(64);
+    let mut args: Vec<&str> = env.iter().skip(1).map(String::as_str).collect();
+    // Opt-in: stop at an interrupt whose vector is still 0000:0000 instead of running whatever is at address 0
+    let stop_on_unset_vector = args.contains(&"--stop-on-unset-vector");
+    args.retain(|arg| *arg != "--stop-on-unset-vector");
+    // The model flag can go anywhere, the last one given wins
+    let mut model = CpuModel::default();
+    args.retain(|arg| {
+        model = match *arg {
+            "--8086" => CpuModel::I8086,
+            "--8088" => CpuModel::I8088,
+            "--80186" => CpuModel::I80186,
+            "--80188" => CpuModel::I80188,
+            _ => return true,
+        };
+        false
+    });
+    match args.as_slice() {
+        [path] => simulate(path, model, stop_on_unset_vector),
+        ["disasm", path] => {
+            let mut buffer = Vec::new();
+            File::open(path).and_then(|mut file| file.read_to_end(&mut buffer)).unwrap();
+            print!("{}", disassemble(&buffer, model));
+        }
+        _ => {
+            println!("Please provide the binary files to simulate");
+            println!("Usage: {} [--8086 | --8088 | --80186 | --80188] [--stop-on-unset-vector] [disasm] <binary>", env[0]);
+            exit(64);
+        }
     }
-    let mut cpu = Cpu::new();
-    let size = read_binary_file(String::from(&env[1]), &mut cpu).unwrap();
-    while cpu.registers.ip < (size as u16) {
-        let instruction = decode(&mut cpu);
-        println!("{:?} ,IP:{}", instruction, cpu.registers.ip);
+}
+
+fn simulate(filepath: &str, model: CpuModel, stop_on_unset_vector: bool) {
+    let mut cpu = Cpu::new(model);
+    let size = read_binary_file(String::from(filepath), &mut cpu).unwrap();
+    println!(
+        "--- {} execution ({}, {}-byte prefetch queue) ---",
+        filepath,
+        model.name(),
+        model.prefetch_queue_size()
+    );
+    let mut total_clocks: u64 = 0;
+    // Runs until HLT, or for programs without one, until IP leaves the loaded image.
+    // Nothing raises the interrupt line here, so a halted CPU would never wake up.
+    while !cpu.halted && cpu.registers.ip < (size as u16) {
+        cpu.last_interrupt = None;
+        let before = Snapshot::of(&cpu);
+        let instruction = match decode(&mut cpu) {
+            Ok(instruction) => instruction,
+            Err(err) => {
+                println!("Failed to decode instruction: {}", err);
+                break;
+            }
+        };
         cpu.execute(&instruction);
+        let after = Snapshot::of(&cpu);
+        match clocks::estimate(&instruction, &before, &after, cpu.model) {
+            Some(clocks) => {
+                total_clocks += clocks.total() as u64;
+                println!(
+                    "{} ; {} | {}",
+                    instruction,
+                    trace::clocks(&clocks, total_clocks),
+                    trace::diff(&before, &after)
+                );
+            }
+            None => println!("{} ; {}", instruction, trace::diff(&before, &after)),
+        }
+        let before = Snapshot::of(&cpu);
+        if let Some(vector) = cpu.service_interrupts() {
+            println!("interrupt {} ; {}", vector, trace::diff(&before, &Snapshot::of(&cpu)));
+        }
+        match cpu.last_interrupt {
+            Some(vector) if stop_on_unset_vector && cpu.interrupt_vector(vector) == (0, 0) => {
+                println!("Interrupt {} has no handler installed (vector is 0000:0000), stopping", vector);
+                break;
+            }
+            _ => {}
+        }
+    }
+    println!();
+    print!("{}", trace::final_registers(&Snapshot::of(&cpu)));
+    // There are no 80186 timing tables, so no total rather than a wrong one
+    if !model.is_80186() {
+        println!("Total clocks: {}", total_clocks);
     }
-    println!("{:?}", cpu.registers)
 }
 
 fn read_binary_file(filepath: String, cpu: &mut Cpu) -> Result<usize, Error> {
     let mut file = File::open(filepath).unwrap();
 
-    file.read(&mut cpu.memory)
+    file.read(&mut cpu.memory[..])
 }
@@ -0,0 +1,88 @@
+use crate::clocks::Clocks;
+use crate::cpu::{ CPURegisters, Cpu, Flags };
+use crate::instruction::Register;
+
+// Registers in the order they are reported in traces
+const TRACED_REGISTERS: [Register; 12] = [
+    Register::AX,
+    Register::BX,
+    Register::CX,
+    Register::DX,
+    Register::SP,
+    Register::BP,
+    Register::SI,
+    Register::DI,
+    Register::ES,
+    Register::CS,
+    Register::SS,
+    Register::DS,
+];
+
+// Register and flag state captured before an instruction runs, to diff against afterwards
+#[derive(Debug, Clone, Copy)]
+pub struct Snapshot {
+    pub registers: CPURegisters,
+    pub flags: Flags,
+}
+
+impl Snapshot {
+    pub fn of(cpu: &Cpu) -> Self {
+        Snapshot { registers: cpu.registers, flags: cpu.flags }
+    }
+}
+
+// Lists every register and the flags that changed between two snapshots,
+// e.g. `cx:0x0->0x3 ip:0x0->0x3 flags:->Z`
+pub fn diff(before: &Snapshot, after: &Snapshot) -> String {
+    let mut changes: Vec<String> = Vec::new();
+    for reg in TRACED_REGISTERS.iter() {
+        let (old, new) = (before.registers.get(reg), after.registers.get(reg));
+        if old != new {
+            changes.push(format!("{}:{:#x}->{:#x}", reg, old, new));
+        }
+    }
+    if before.registers.ip != after.registers.ip {
+        changes.push(format!("ip:{:#x}->{:#x}", before.registers.ip, after.registers.ip));
+    }
+    let (old_flags, new_flags) = (before.flags.to_string(), after.flags.to_string());
+    if old_flags != new_flags {
+        changes.push(format!("flags:{}->{}", old_flags, new_flags));
+    }
+    changes.join(" ")
+}
+
+// `Clocks: +13 = 17 (8 + 5ea)`, the breakdown is only shown when there is an EA or penalty part
+pub fn clocks(clocks: &Clocks, total: u64) -> String {
+    let mut output = format!("Clocks: +{} = {}", clocks.total(), total);
+    if clocks.ea != 0 || clocks.penalty != 0 {
+        output.push_str(&format!(" ({}", clocks.base));
+        if clocks.ea != 0 {
+            output.push_str(&format!(" + {}ea", clocks.ea));
+        }
+        if clocks.penalty != 0 {
+            output.push_str(&format!(" + {}p", clocks.penalty));
+        }
+        output.push(')');
+    }
+    output
+}
+
+// The "Final registers" block printed at the end of a run. Registers that are zero are left out.
+pub fn final_registers(snapshot: &Snapshot) -> String {
+    let mut output = String::from("Final registers:\n");
+    for reg in TRACED_REGISTERS.iter() {
+        let val = snapshot.registers.get(reg);
+        if val != 0 {
+            output.push_str(&format!("{:>8}: {:#06x} ({})\n", reg.name(), val, val));
+        }
+    }
+    let ip = snapshot.registers.ip;
+    if ip != 0 {
+        output.push_str(&format!("{:>8}: {:#06x} ({})\n", "ip", ip, ip));
+    }
+    let flags = snapshot.flags.to_string();
+    if !flags.is_empty() {
+        output.push_str(&format!("{:>8}: {}\n", "flags", flags));
+    }
+    output
+}
@@ -0,0 +1,33 @@
+use sim_8086::cpu::CpuModel;
+use sim_8086::disasm::disassemble;
+
+// The expected NASM source for each listing in assets/ is kept in tests/golden/
+fn assert_golden(listing: &str) {
+    let root = env!("CARGO_MANIFEST_DIR");
+    let image = std::fs::read(format!("{}/assets/{}", root, listing)).unwrap();
+    let expected = std::fs::read_to_string(format!("{}/tests/golden/{}.asm", root, listing)).unwrap();
+    assert_eq!(disassemble(&image, CpuModel::I8086), expected, "{}", listing);
+}
+
+#[test]
+fn golden_listings() {
+    let listings = [
+        "listing_0037_single_register_mov",
+        "listing_0038_many_register_mov",
+        "listing_0039_more_movs",
+        "listing_0040_challenge_movs",
+        "listing_0041_add_sub_cmp_jnz",
+        "listing_0043_immediate_movs",
+        "listing_0044_register_movs",
+        "listing_0046_add_sub_cmp",
+        "listing_0047_challenge_flags",
+        "listing_0048_ip_register",
+        "listing_0049_conditional_jumps",
+        "listing_0051_memory_mov",
+        "listing_0052_memory_add_loop",
+        "listing_0053_add_loop_challenge",
+    ];
+    for listing in listings {
+        assert_golden(listing);
+    }
+}
@@ -0,0 +1,18 @@
+bits 16
+
+mov dx, 6
+mov bp, 1000
+mov si, 0
+label_0:
+mov [bp + si], si
+add si, 2
+cmp si, dx
+jne label_0
+mov bx, 0
+mov si, 0
+label_1:
+mov cx, [bp + si]
+add bx, cx
+add si, 2
+cmp si, dx
+jne label_1
@@ -0,0 +1,17 @@
+bits 16
+
+mov dx, 6
+mov bp, 1000
+mov si, 0
+label_0:
+mov [bp + si], si
+add si, 2
+cmp si, dx
+jne label_0
+mov bx, 0
+mov si, dx
+sub bp, 2
+label_1:
+add bx, [bp + si]
+sub si, 2
+jne label_1
@@ -0,0 +1,118 @@
+use sim_8086::cpu::{ Cpu, CpuModel };
+use sim_8086::decoder::decode;
+use sim_8086::instruction::Register;
+
+// Runs a listing from assets/ the way main does, until HLT or IP leaves the loaded image
+fn run(listing: &str) -> Cpu {
+    let path = format!("{}/assets/{}", env!("CARGO_MANIFEST_DIR"), listing);
+    let image = std::fs::read(path).unwrap();
+    let mut cpu = Cpu::new(CpuModel::I8086);
+    cpu.memory[..image.len()].copy_from_slice(&image);
+    while !cpu.halted && (cpu.registers.ip as usize) < image.len() {
+        let instruction = decode(&mut cpu).unwrap();
+        cpu.execute(&instruction);
+    }
+    cpu
+}
+
+// Registers left out of `expected` must be zero, as in the "Final registers" block
+fn assert_final_registers(listing: &str, expected: &[(Register, u16)], ip: u16, flags: &str) {
+    let cpu = run(listing);
+    let registers = [
+        Register::AX,
+        Register::BX,
+        Register::CX,
+        Register::DX,
+        Register::SP,
+        Register::BP,
+        Register::SI,
+        Register::DI,
+        Register::ES,
+        Register::CS,
+        Register::SS,
+        Register::DS,
+    ];
+    for register in registers {
+        let value = expected.iter().find(|(reg, _)| *reg == register).map_or(0, |(_, val)| *val);
+        assert_eq!(cpu.registers.get(&register), value, "{} in {}", register, listing);
+    }
+    assert_eq!(cpu.registers.ip, ip, "ip in {}", listing);
+    assert_eq!(cpu.flags.to_string(), flags, "flags in {}", listing);
+}
+
+#[test]
+fn listing_0043_immediate_movs() {
+    let expected = [
+        (Register::AX, 1),
+        (Register::BX, 2),
+        (Register::CX, 3),
+        (Register::DX, 4),
+        (Register::SP, 5),
+        (Register::BP, 6),
+        (Register::SI, 7),
+        (Register::DI, 8),
+    ];
+    assert_final_registers("listing_0043_immediate_movs", &expected, 0x18, "");
+}
+
+#[test]
+fn listing_0044_register_movs() {
+    let expected = [
+        (Register::AX, 4),
+        (Register::BX, 3),
+        (Register::CX, 2),
+        (Register::DX, 1),
+        (Register::SP, 1),
+        (Register::BP, 2),
+        (Register::SI, 3),
+        (Register::DI, 4),
+    ];
+    assert_final_registers("listing_0044_register_movs", &expected, 0x1c, "");
+}
+
+#[test]
+fn listing_0046_add_sub_cmp() {
+    let expected = [(Register::BX, 0xe102), (Register::CX, 0x0f01), (Register::SP, 0x03e6)];
+    assert_final_registers("listing_0046_add_sub_cmp", &expected, 0x18, "PZ");
+}
+
+#[test]
+fn listing_0047_challenge_flags() {
+    let expected = [(Register::BX, 0x9ca5), (Register::DX, 0x000a), (Register::SP, 0x0063), (Register::BP, 0x0062)];
+    assert_final_registers("listing_0047_challenge_flags", &expected, 0x2c, "CPAS");
+}
+
+#[test]
+fn listing_0048_ip_register() {
+    let expected = [(Register::BX, 0x07d0), (Register::CX, 0xfce0)];
+    assert_final_registers("listing_0048_ip_register", &expected, 0x0e, "CS");
+}
+
+#[test]
+fn listing_0049_conditional_jumps() {
+    assert_final_registers("listing_0049_conditional_jumps", &[(Register::BX, 0x0406)], 0x0e, "PZ");
+}
+
+#[test]
+fn listing_0051_memory_mov() {
+    let expected = [(Register::BX, 1), (Register::CX, 2), (Register::DX, 10), (Register::BP, 4)];
+    assert_final_registers("listing_0051_memory_mov", &expected, 0x30, "");
+}
+
+#[test]
+fn listing_0052_memory_add_loop() {
+    let expected = [
+        (Register::BX, 6),
+        (Register::CX, 4),
+        (Register::DX, 6),
+        (Register::BP, 1000),
+        (Register::SI, 6),
+    ];
+    assert_final_registers("listing_0052_memory_add_loop", &expected, 0x23, "PZ");
+}
+
+#[test]
+fn listing_0053_add_loop_challenge() {
+    let expected = [(Register::BX, 6), (Register::DX, 6), (Register::BP, 998)];
+    assert_final_registers("listing_0053_add_loop_challenge", &expected, 0x21, "PZ");
+}